@@ -28,7 +28,7 @@ source /etc/os-release
 
 case "$ID" in
     fedora)
-        dnf install -y postgresql-server postgis ffmpeg pkg-config openssl-devel npm protobuf-compiler protobuf-devel
+        dnf install -y postgresql-server postgis ffmpeg libheif-devel pkg-config openssl-devel npm protobuf-compiler protobuf-devel
 
         pgdata="/var/lib/pgsql/data"
         if [[ ! -d $pgdata || ! "$(ls -A $pgdata)" ]]; then
@@ -40,7 +40,7 @@ case "$ID" in
         ;;
     ubuntu)
         apt-get update
-        apt-get install -y postgresql postgis ffmpeg pkg-config npm protobuf-compiler libprotobuf-dev nodejs-dev node-gyp libssl1.0-dev
+        apt-get install -y postgresql postgis ffmpeg libheif-dev pkg-config npm protobuf-compiler libprotobuf-dev nodejs-dev node-gyp libssl1.0-dev
         service postgresql start
         ;;
     *)
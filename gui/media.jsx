@@ -17,6 +17,7 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
+import { decode } from "blurhash";
 import update from "immutability-helper";
 import React from "react";
 import { confirmAlert } from "react-confirm-alert";
@@ -32,6 +33,41 @@ import { SimpleSearch } from "./widgets/search.jsx";
 import listClasses from "./css/media-list.css";
 import viewClasses from "./css/media-view.css";
 
+// Build a srcset from the derivatives that look like the given profile, only differing in size
+function derivativeSrcSet(entity, profile) {
+    const derivatives = entity.derivatives || [];
+    const base = derivatives.find((d) => d.profile === profile);
+    if (!base) {
+        return undefined;
+    }
+    const aspectRatio = base.width / base.height;
+    return derivatives
+        .filter((d) => d.fit === base.fit && Math.abs(d.width / d.height - aspectRatio) < 0.01)
+        .map((d) => `${d.url} ${d.width}w`)
+        .join(", ");
+}
+
+const placeholderSize = 32;
+const placeholderCache = new Map();
+
+// Decode the BlurHash of an entity into a tiny image to show while the thumbnail loads
+function placeholderUrl(entity) {
+    if (!entity.blurhash) {
+        return undefined;
+    }
+    if (!placeholderCache.has(entity.blurhash)) {
+        const canvas = document.createElement("canvas");
+        canvas.width = placeholderSize;
+        canvas.height = placeholderSize;
+        const ctx = canvas.getContext("2d");
+        const imageData = ctx.createImageData(placeholderSize, placeholderSize);
+        imageData.data.set(decode(entity.blurhash, placeholderSize, placeholderSize));
+        ctx.putImageData(imageData, 0, 0);
+        placeholderCache.set(entity.blurhash, canvas.toDataURL());
+    }
+    return placeholderCache.get(entity.blurhash);
+}
+
 function getFormattedDate(timestamp) {
     const date = new Date(timestamp * 1000);
     const year = date.getFullYear();
@@ -124,6 +160,29 @@ class PlayButton extends React.Component {
     }
 }
 
+const EXPOSURE_PROGRAMS = [
+    null,
+    "Manual",
+    "Program",
+    "Aperture priority",
+    "Shutter priority",
+    "Creative",
+    "Action",
+    "Portrait",
+    "Landscape",
+];
+
+const METERING_MODES = [
+    null,
+    "Average",
+    "Center weighted",
+    "Spot",
+    "Multi-spot",
+    "Pattern",
+    "Partial",
+    "Other",
+];
+
 function Metadata(props) {
     /* eslint-disable camelcase */
     const {
@@ -131,11 +190,12 @@ function Metadata(props) {
         filename,
         locationName,
         created,
+        createdLocal,
         uploaded,
         ...attrs
     } = props;
     const {
-        width, height, rotation, type_specific,
+        width, height, rotation, type_specific, has_utc_offset, utc_offset,
     } = metadata;
 
     const items = [];
@@ -145,7 +205,22 @@ function Metadata(props) {
         items.push(<dd key="filename_value">{filename}</dd>);
     }
 
-    if (created != null) {
+    if (createdLocal != null) {
+        // The time on the camera's clock, with its offset from UTC when known
+        let offset = "";
+        if (has_utc_offset) {
+            const minutes = Math.abs(utc_offset) / 60;
+            const hours = `0${Math.floor(minutes / 60)}`.substr(-2);
+            offset = ` UTC${utc_offset < 0 ? "-" : "+"}${hours}:${`0${minutes % 60}`.substr(-2)}`;
+        }
+        items.push(<dt key="created_key">Taken</dt>);
+        items.push(
+            <dd key="created_value">
+                {getFormattedDate(createdLocal.seconds)}
+                {offset}
+            </dd>,
+        );
+    } else if (created != null) {
         items.push(<dt key="created_key">Taken</dt>);
         items.push(<dd key="created_value">{getFormattedDate(created.seconds)}</dd>);
     }
@@ -206,13 +281,64 @@ function Metadata(props) {
     case "image": {
         const {
             image: {
-                exposure_time, aperture, iso, flash,
+                exposure_time, aperture, iso, flash, camera_make, camera_model, lens_model,
+                focal_length, focal_length_35mm, exposure_program, metering_mode, white_balance,
             },
         } = metadata;
 
-        if (exposure_time != null && exposure_time !== 0) {
+        if (camera_make || camera_model) {
+            // Most cameras repeat the make in the model
+            const camera = camera_model && camera_make && !camera_model.startsWith(camera_make)
+                ? `${camera_make} ${camera_model}`
+                : camera_model || camera_make;
+            items.push(<dt key="camera_key">Camera</dt>);
+            items.push(<dd key="camera_value">{camera}</dd>);
+        }
+
+        if (lens_model) {
+            items.push(<dt key="lens_key">Lens</dt>);
+            items.push(<dd key="lens_value">{lens_model}</dd>);
+        }
+
+        if (focal_length != null && focal_length !== 0) {
+            const equivalent = focal_length_35mm && Math.round(focal_length_35mm) !== Math.round(focal_length)
+                ? ` (${Math.round(focal_length_35mm)} mm in 35mm)`
+                : "";
+            items.push(<dt key="focal_length_key">Focal length</dt>);
+            items.push(
+                <dd key="focal_length_value">
+                    {`${focal_length.toFixed(1).replace(/\.0$/, "")} mm${equivalent}`}
+                </dd>,
+            );
+        }
+
+        if (exposure_time) {
             items.push(<dt key="exposure_time_key">Exposure time</dt>);
-            items.push(<dd key="exposure_time_value">{exposure_time}</dd>);
+            items.push(
+                <dd key="exposure_time_value">
+                    {exposure_time}
+                    {" s"}
+                </dd>,
+            );
+        }
+
+        if (exposure_program) {
+            items.push(<dt key="exposure_program_key">Exposure program</dt>);
+            items.push(
+                <dd key="exposure_program_value">{EXPOSURE_PROGRAMS[exposure_program]}</dd>,
+            );
+        }
+
+        if (metering_mode) {
+            items.push(<dt key="metering_mode_key">Metering</dt>);
+            items.push(<dd key="metering_mode_value">{METERING_MODES[metering_mode]}</dd>);
+        }
+
+        if (white_balance) {
+            items.push(<dt key="white_balance_key">White balance</dt>);
+            items.push(
+                <dd key="white_balance_value">{white_balance === 1 ? "Auto" : "Manual"}</dd>,
+            );
         }
 
         if (iso != null && iso !== 0) {
@@ -275,7 +401,7 @@ function VideoPlayer(props) {
         // eslint-disable-next-line jsx-a11y/media-has-caption
         <video
             className={viewClasses.preview}
-            src={`/assets/${entity.path}`}
+            src={`/assets/${entity.id}/original`}
             controls
             autoPlay
         />
@@ -394,7 +520,7 @@ class Pic extends React.Component {
         const tags = [];
         if (fullEntity !== null) {
             const {
-                uploaded, created, location, metadata, tags: tagList,
+                uploaded, created, created_local: createdLocal, location, metadata, tags: tagList,
             } = fullEntity;
 
             if (tagList.tag.length) {
@@ -444,9 +570,10 @@ class Pic extends React.Component {
                 <div className={viewClasses.metadata}>
                     <Metadata
                         data={metadata}
-                        filename={simpleEntity.path.replace("dest/", "")}
+                        filename={simpleEntity.original_name}
                         locationName={location ? location.place : null}
                         created={created}
+                        createdLocal={createdLocal}
                         uploaded={uploaded}
                     />
                     {map}
@@ -463,7 +590,15 @@ class Pic extends React.Component {
         let previewImg = null;
         let videoPlayer = null;
         if (!playClicked) {
-            previewImg = <img className={viewClasses.preview} src={`/assets/${simpleEntity.preview_path}`} alt="" />;
+            previewImg = (
+                <img
+                    className={viewClasses.preview}
+                    src={`/assets/${simpleEntity.id}/preview`}
+                    srcSet={derivativeSrcSet(simpleEntity, "preview")}
+                    sizes="100vw"
+                    alt=""
+                />
+            );
         }
         if (playClicked) {
             videoPlayer = <VideoPlayer entity={simpleEntity} />;
@@ -525,9 +660,20 @@ function MediaList({ entities }) {
             if (entity.media_type === 1) {
                 overlay = <PlayButton />;
             }
+            // Reserve the space of the thumbnail so the placeholder is visible while it loads
+            const thumbnail = (entity.derivatives || []).find((d) => d.profile === "thumbnail");
+            const placeholder = placeholderUrl(entity);
             entityLinks.push(
                 <PreserveQueryParamsLink className={listClasses.thumbnail} key={entity.id} to={`/media/${entity.id}`}>
-                    <img src={`/assets/${entity.thumbnail_path}`} alt="" />
+                    <img
+                        src={`/assets/${entity.id}/thumbnail`}
+                        srcSet={derivativeSrcSet(entity, "thumbnail")}
+                        sizes="300px"
+                        width={thumbnail && thumbnail.width}
+                        height={thumbnail && thumbnail.height}
+                        style={placeholder && { backgroundImage: `url(${placeholder})` }}
+                        alt=""
+                    />
                     {overlay}
                 </PreserveQueryParamsLink>,
             );
@@ -23,26 +23,172 @@ use std::convert::TryFrom;
 use std::convert::TryInto;
 use tokio_postgres::{Client, Row};
 
+use crate::edit::EditOperation as DbEditOperation;
 use crate::error::{Error, Result};
 use crate::metadata::Metadata as FileMetadata;
 use crate::metadata::Rotate as FileRotation;
-use crate::metadata::TypeSpecific;
+use crate::metadata::{ExposureProgram, MeteringMode, TypeSpecific, WhiteBalance};
+use crate::model::Derivative as DbDerivative;
+use crate::model::Edit as DbEdit;
 use crate::model::Entity as DbEntity;
 use crate::model::EntityType as DbEntityType;
 use crate::model::Tag as DbTag;
+use crate::regenerate::RegenerateSummary;
+use crate::time_correction::TimeCorrectionSummary;
 include!(concat!(env!("OUT_DIR"), "/api.rs"));
 
 impl Entity {
     pub fn new_from_db<T: IntoIterator<Item = DbTag>>(
         db_entity: DbEntity,
         db_tags: T,
+        metadata: Option<FileMetadata>,
     ) -> Result<Self> {
         let mut tags = Tags::default();
         for db_tag in db_tags {
             tags.add(db_tag.try_into()?);
         }
 
-        create_entity_with_metadata(db_entity, tags)
+        create_entity_with_metadata(db_entity, tags, metadata)
+    }
+
+    /// Convert an entity together with its stored metadata, if any
+    pub fn with_metadata(db_entity: DbEntity, metadata: Option<FileMetadata>) -> Result<Self> {
+        let rotation = db_entity.rotation.clone();
+        let utc_offset = db_entity.utc_offset;
+        let mut pb_entity = Entity::try_from(db_entity)?;
+        if let Some(mut metadata) = metadata {
+            // A rotation set by the user wins over the one in the file
+            if rotation.is_some() {
+                metadata.rotation = rotation;
+            }
+            // The capture time may have been corrected since the file was read
+            metadata.utc_offset = utc_offset;
+            pb_entity.metadata = Some(Metadata::try_from(metadata)?);
+        }
+        Ok(pb_entity)
+    }
+}
+
+impl Entity {
+    pub fn add_derivatives<T: IntoIterator<Item = DbDerivative>>(
+        &mut self,
+        db_derivatives: T,
+    ) -> Result<()> {
+        for db_derivative in db_derivatives {
+            self.derivatives.push(db_derivative.try_into()?);
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<DbDerivative> for Derivative {
+    type Error = Error;
+    fn try_from(db_derivative: DbDerivative) -> Result<Derivative> {
+        let mut derivative = Derivative::default();
+        derivative.url = format!("/assets/{}/{}", db_derivative.eid, db_derivative.profile);
+        derivative.profile = db_derivative.profile;
+        derivative.width = db_derivative.width;
+        derivative.height = db_derivative.height;
+        derivative.fit = db_derivative.fit;
+        Ok(derivative)
+    }
+}
+
+impl TryFrom<EditOperation> for DbEditOperation {
+    type Error = Error;
+    fn try_from(edit_operation: EditOperation) -> Result<DbEditOperation> {
+        let operation = match edit_operation
+            .operation
+            .ok_or(anyhow!("Edit operation is missing"))?
+        {
+            edit_operation::Operation::Crop(crop) => DbEditOperation::Crop {
+                x: crop.x,
+                y: crop.y,
+                width: crop.width,
+                height: crop.height,
+            },
+            edit_operation::Operation::Straighten(degrees) => {
+                DbEditOperation::Straighten { degrees }
+            }
+            edit_operation::Operation::Rotate(turns) => DbEditOperation::Rotate { turns },
+            edit_operation::Operation::Exposure(ev) => DbEditOperation::Exposure { ev },
+            edit_operation::Operation::Contrast(amount) => DbEditOperation::Contrast { amount },
+            edit_operation::Operation::WhiteBalance(white_balance) => {
+                DbEditOperation::WhiteBalance {
+                    temperature: white_balance.temperature,
+                    tint: white_balance.tint,
+                }
+            }
+        };
+        operation.validate()?;
+        Ok(operation)
+    }
+}
+
+impl From<DbEditOperation> for EditOperation {
+    fn from(db_operation: DbEditOperation) -> EditOperation {
+        let operation = match db_operation {
+            DbEditOperation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => edit_operation::Operation::Crop(edit_operation::Crop {
+                x,
+                y,
+                width,
+                height,
+            }),
+            DbEditOperation::Straighten { degrees } => {
+                edit_operation::Operation::Straighten(degrees)
+            }
+            DbEditOperation::Rotate { turns } => edit_operation::Operation::Rotate(turns),
+            DbEditOperation::Exposure { ev } => edit_operation::Operation::Exposure(ev),
+            DbEditOperation::Contrast { amount } => edit_operation::Operation::Contrast(amount),
+            DbEditOperation::WhiteBalance { temperature, tint } => {
+                edit_operation::Operation::WhiteBalance(edit_operation::WhiteBalance {
+                    temperature,
+                    tint,
+                })
+            }
+        };
+        EditOperation {
+            operation: Some(operation),
+        }
+    }
+}
+
+impl Edits {
+    /// Edits of an entity that has never been edited
+    pub fn empty(eid: i32) -> Self {
+        let mut edits = Edits::default();
+        edits.eid = eid;
+        edits
+    }
+
+    pub fn to_operations(&self) -> Result<Vec<DbEditOperation>> {
+        self.operations
+            .iter()
+            .cloned()
+            .map(DbEditOperation::try_from)
+            .collect()
+    }
+}
+
+impl TryFrom<DbEdit> for Edits {
+    type Error = Error;
+    fn try_from(db_edit: DbEdit) -> Result<Edits> {
+        let mut edits = Edits::empty(db_edit.eid);
+        edits.operations = db_edit
+            .operations
+            .into_iter()
+            .map(EditOperation::from)
+            .collect();
+        edits.updated = Some(Timestamp {
+            seconds: db_edit.updated.timestamp(),
+            nanos: db_edit.updated.timestamp_subsec_nanos().try_into()?,
+        });
+        Ok(edits)
     }
 }
 
@@ -71,6 +217,9 @@ impl TryFrom<DbEntity> for Entity {
             .to_str()
             .ok_or(anyhow!("Could not convert path"))?
             .to_string();
+        entity.original_name = db_entity.original_name;
+        entity.missing = db_entity.missing;
+        entity.blurhash = db_entity.blurhash.unwrap_or_default();
         let uploaded = db_entity.uploaded;
         entity.uploaded = Some(Timestamp {
             seconds: uploaded.timestamp(),
@@ -82,6 +231,12 @@ impl TryFrom<DbEntity> for Entity {
                 nanos: v.timestamp_subsec_nanos().try_into()?,
             });
         }
+        if let Some(v) = db_entity.created_local {
+            entity.created_local = Some(Timestamp {
+                seconds: v.timestamp(),
+                nanos: v.timestamp_subsec_nanos().try_into()?,
+            });
+        }
         if let Some(v) = db_entity.location {
             let mut location = entity::Location::default();
             location.latitude = v.latitude;
@@ -102,29 +257,83 @@ impl Entities {
 pub fn create_entity_with_metadata(
     db_entity: DbEntity,
     tags: Tags,
+    metadata: Option<FileMetadata>,
 ) -> crate::error::Result<Entity> {
-    let mut pb_entity = Entity::try_from(db_entity)?;
-    let path = &pb_entity.path;
-    let metadata = FileMetadata::from_file(&path)?;
-    if let Some(v) = metadata.date_time {
-        pb_entity.created = Some(Timestamp {
-            seconds: v.timestamp(),
-            nanos: v.timestamp_subsec_nanos().try_into()?,
-        });
-    }
-    let pb_metadata = Metadata::try_from(metadata)?;
-    pb_entity.metadata = Some(pb_metadata);
+    let mut pb_entity = Entity::with_metadata(db_entity, metadata)?;
     pb_entity.tags = Some(tags);
 
     Ok(pb_entity)
 }
 
+impl TryFrom<TimeCorrectionSummary> for TimeCorrection {
+    type Error = Error;
+    fn try_from(summary: TimeCorrectionSummary) -> Result<TimeCorrection> {
+        let timestamp = |seconds: i64, nanos: u32| -> Result<Timestamp> {
+            Ok(Timestamp {
+                seconds,
+                nanos: nanos.try_into()?,
+            })
+        };
+        let mut time_correction = TimeCorrection::default();
+        time_correction.id = summary.id.unwrap_or_default();
+        for db_change in summary.changes {
+            let mut change = time_correction::Change::default();
+            change.id = db_change.eid;
+            change.old_created = db_change
+                .old_created
+                .map(|v| timestamp(v.timestamp(), v.timestamp_subsec_nanos()))
+                .transpose()?;
+            change.old_created_local = db_change
+                .old_created_local
+                .map(|v| timestamp(v.timestamp(), v.timestamp_subsec_nanos()))
+                .transpose()?;
+            change.new_created = db_change
+                .new_created
+                .map(|v| timestamp(v.timestamp(), v.timestamp_subsec_nanos()))
+                .transpose()?;
+            change.new_created_local = db_change
+                .new_created_local
+                .map(|v| timestamp(v.timestamp(), v.timestamp_subsec_nanos()))
+                .transpose()?;
+            time_correction.changes.push(change);
+        }
+        time_correction.skipped = summary.skipped;
+        time_correction.sidecars_failed = summary
+            .sidecars_failed
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        Ok(time_correction)
+    }
+}
+
+impl RotationOverride {
+    /// Returns the rotation to use, `None` means the one in the file
+    pub fn to_rotation(&self) -> Result<Option<FileRotation>> {
+        if self.reset {
+            return Ok(None);
+        }
+        let rotation = match metadata::Rotation::from_i32(self.rotation) {
+            Some(metadata::Rotation::Zero) => FileRotation::Zero,
+            Some(metadata::Rotation::Cw90) => FileRotation::Cw90,
+            Some(metadata::Rotation::Ccw90) => FileRotation::Ccw90,
+            Some(metadata::Rotation::Cw180) => FileRotation::Cw180,
+            None => return Err(anyhow!("Unknown rotation {}", self.rotation).into()),
+        };
+        Ok(Some(rotation))
+    }
+}
+
 impl TryFrom<FileMetadata> for Metadata {
     type Error = Error;
     fn try_from(file_metadata: FileMetadata) -> Result<Metadata> {
         let mut metadata = Metadata::default();
         metadata.width = file_metadata.width;
         metadata.height = file_metadata.height;
+        if let Some(v) = file_metadata.utc_offset {
+            metadata.has_utc_offset = true;
+            metadata.utc_offset = v;
+        }
         if let Some(v) = file_metadata.rotation {
             metadata.rotation = match v {
                 FileRotation::Zero => metadata::Rotation::Zero.into(),
@@ -145,6 +354,56 @@ impl TryFrom<FileMetadata> for Metadata {
                 if let Some(v) = file_img_metadata.flash {
                     img_metadata.flash = v;
                 }
+                if let Some(v) = file_img_metadata.exposure_time {
+                    img_metadata.exposure_time = v.to_string();
+                }
+                img_metadata.camera_make = file_img_metadata.camera_make.unwrap_or_default();
+                img_metadata.camera_model = file_img_metadata.camera_model.unwrap_or_default();
+                img_metadata.lens_model = file_img_metadata.lens_model.unwrap_or_default();
+                if let Some(v) = file_img_metadata.focal_length {
+                    img_metadata.focal_length = v.into();
+                }
+                if let Some(v) = file_img_metadata.focal_length_35mm {
+                    img_metadata.focal_length_35mm = v.into();
+                }
+                if let Some(v) = file_img_metadata.exposure_program {
+                    img_metadata.exposure_program = match v {
+                        ExposureProgram::Manual => metadata::image::ExposureProgram::Manual,
+                        ExposureProgram::Normal => metadata::image::ExposureProgram::Normal,
+                        ExposureProgram::AperturePriority => {
+                            metadata::image::ExposureProgram::AperturePriority
+                        }
+                        ExposureProgram::ShutterPriority => {
+                            metadata::image::ExposureProgram::ShutterPriority
+                        }
+                        ExposureProgram::Creative => metadata::image::ExposureProgram::Creative,
+                        ExposureProgram::Action => metadata::image::ExposureProgram::Action,
+                        ExposureProgram::Portrait => metadata::image::ExposureProgram::Portrait,
+                        ExposureProgram::Landscape => metadata::image::ExposureProgram::Landscape,
+                    }
+                    .into();
+                }
+                if let Some(v) = file_img_metadata.metering_mode {
+                    img_metadata.metering_mode = match v {
+                        MeteringMode::Average => metadata::image::MeteringMode::Average,
+                        MeteringMode::CenterWeighted => {
+                            metadata::image::MeteringMode::CenterWeighted
+                        }
+                        MeteringMode::Spot => metadata::image::MeteringMode::Spot,
+                        MeteringMode::MultiSpot => metadata::image::MeteringMode::MultiSpot,
+                        MeteringMode::Pattern => metadata::image::MeteringMode::Pattern,
+                        MeteringMode::Partial => metadata::image::MeteringMode::Partial,
+                        MeteringMode::Other => metadata::image::MeteringMode::OtherMetering,
+                    }
+                    .into();
+                }
+                if let Some(v) = file_img_metadata.white_balance {
+                    img_metadata.white_balance = match v {
+                        WhiteBalance::Auto => metadata::image::WhiteBalance::AutoWhiteBalance,
+                        WhiteBalance::Manual => metadata::image::WhiteBalance::ManualWhiteBalance,
+                    }
+                    .into();
+                }
                 metadata.type_specific = Some(metadata::TypeSpecific::Image(img_metadata));
             }
             TypeSpecific::Video(file_video_metadata) => {
@@ -219,3 +478,17 @@ impl AutocompleteTags {
         Ok(tags)
     }
 }
+
+impl From<RegenerateSummary> for RegenerateResult {
+    fn from(summary: RegenerateSummary) -> RegenerateResult {
+        let mut result = RegenerateResult::default();
+        result.regenerated = summary.regenerated;
+        for (id, reason) in summary.failed {
+            let mut failure = regenerate_result::Failure::default();
+            failure.id = id;
+            failure.reason = reason;
+            result.failed.push(failure);
+        }
+        result
+    }
+}
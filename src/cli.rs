@@ -17,9 +17,14 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
+use chrono::{DateTime, Utc};
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+use crate::error::Result;
+use crate::metadata::Rotate;
+use crate::model::{EntityFilter, EntityType, Tag};
+
 #[derive(Debug, StructOpt)]
 pub enum Cmd {
     /// Start the application web service [default]
@@ -30,6 +35,146 @@ pub enum Cmd {
         /// One or more files or directories to import
         #[structopt(parse(from_os_str), required = true)]
         paths: Vec<PathBuf>,
+
+        /// Number of files to process in parallel. Defaults to import.workers in config.toml
+        #[structopt(short = "j", long = "jobs")]
+        jobs: Option<usize>,
+    },
+
+    /// Watch folders and import supported media as it's added
+    Watch {
+        /// One or more directories to watch
+        #[structopt(parse(from_os_str), required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Number of files to process in parallel. Defaults to import.workers in config.toml
+        #[structopt(short = "j", long = "jobs")]
+        jobs: Option<usize>,
+
+        /// Seconds a file must be left untouched before it's considered completely written
+        #[structopt(short = "d", long = "delay", default_value = "2")]
+        delay: u64,
+    },
+
+    /// Compare library folders with the database to find moved, deleted, modified and new files
+    Rescan {
+        /// Directories to scan. Defaults to all libraries in config.toml
+        #[structopt(parse(from_os_str))]
+        paths: Vec<PathBuf>,
+
+        /// Update the database instead of only reporting the changes
+        #[structopt(long = "apply")]
+        apply: bool,
+
+        /// Number of files to process in parallel. Defaults to import.workers in config.toml
+        #[structopt(short = "j", long = "jobs")]
+        jobs: Option<usize>,
+    },
+
+    /// Check that originals, thumbnails and previews are present and intact
+    Verify {
+        /// Regenerate missing thumbnails and previews
+        #[structopt(long = "regenerate")]
+        regenerate: bool,
+
+        /// Delete files in dest that don't belong to any media
+        #[structopt(long = "delete-orphans")]
+        delete_orphans: bool,
+
+        /// Number of files to process in parallel. Defaults to import.workers in config.toml
+        #[structopt(short = "j", long = "jobs")]
+        jobs: Option<usize>,
+    },
+
+    /// Rebuild thumbnails and previews from the originals. Without filters all media is rebuilt
+    Regenerate {
+        #[structopt(flatten)]
+        filter: FilterArgs,
+
+        /// Number of files to process in parallel. Defaults to import.workers in config.toml
+        #[structopt(short = "j", long = "jobs")]
+        jobs: Option<usize>,
+    },
+
+    /// Read the metadata of the originals again and store it. Without filters all media is read
+    RefreshMetadata {
+        #[structopt(flatten)]
+        filter: FilterArgs,
+
+        /// Number of files to process in parallel. Defaults to import.workers in config.toml
+        #[structopt(short = "j", long = "jobs")]
+        jobs: Option<usize>,
+    },
+
+    /// Shift the capture time of media by a fixed amount, or set it. Without --apply the changes
+    /// are only shown
+    CorrectTime {
+        #[structopt(flatten)]
+        filter: FilterArgs,
+
+        /// Amount to shift by, i.e. +1h30m, -2d or -01:00
+        #[structopt(long = "shift", allow_hyphen_values = true, required_unless = "set")]
+        shift: Option<String>,
+
+        /// Time to set, i.e. 2020-06-01T12:00:00+02:00. Without an offset the one of each media
+        /// is kept
+        #[structopt(long = "set", conflicts_with = "shift")]
+        set: Option<String>,
+
+        /// Change the capture times instead of only showing the changes
+        #[structopt(long = "apply")]
+        apply: bool,
+
+        /// Write the new capture time to the XMP sidecar of each original as well
+        #[structopt(long = "write-sidecars")]
+        write_sidecars: bool,
+    },
+
+    /// Put back the capture times changed by correct-time
+    UndoCorrectTime {
+        /// Id of the correction, as shown by correct-time
+        id: i32,
+    },
+
+    /// Compute the placeholders shown while thumbnails load for media that doesn't have one
+    Placeholders {
+        /// Recompute the placeholders of all media
+        #[structopt(long = "all")]
+        all: bool,
+
+        /// Number of files to process in parallel. Defaults to import.workers in config.toml
+        #[structopt(short = "j", long = "jobs")]
+        jobs: Option<usize>,
+    },
+
+    /// Set the rotation of media whose orientation is wrong or missing
+    Rotate {
+        /// Id of media to rotate
+        #[structopt(short = "i", long = "id")]
+        id: i32,
+
+        /// Degrees clockwise, 0, 90, 180 or 270
+        #[structopt(required_unless = "reset")]
+        rotation: Option<Rotate>,
+
+        /// Use the orientation stored in the file again
+        #[structopt(long = "reset")]
+        reset: bool,
+    },
+
+    /// Save a full size copy of the media with its edits applied
+    Export {
+        /// Id of media to export
+        #[structopt(short = "i", long = "id")]
+        id: i32,
+
+        /// Where to save the copy. The format is picked from the extension, jpg, png, webp or avif
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+
+        /// From 1 to 100. Defaults to render.quality in config.toml
+        #[structopt(short = "q", long = "quality")]
+        quality: Option<u8>,
     },
 
     /// Initialize database
@@ -39,6 +184,10 @@ pub enum Cmd {
     Metadata {
         #[structopt(parse(from_os_str))]
         path: PathBuf,
+
+        /// Show every EXIF, XMP and IPTC field as JSON
+        #[structopt(long = "raw")]
+        raw: bool,
     },
 
     Search {
@@ -58,6 +207,48 @@ pub enum Cmd {
     },
 }
 
+/// Selects the media a command works on
+#[derive(Debug, StructOpt)]
+pub struct FilterArgs {
+    /// Only media with the given id. May be given several times
+    #[structopt(short = "i", long = "id")]
+    ids: Vec<i32>,
+
+    /// Only media of the given type, image or video
+    #[structopt(long = "type")]
+    media_type: Option<EntityType>,
+
+    /// Only media with the given tag or any of its children
+    #[structopt(short = "t", long = "tag")]
+    tag: Option<String>,
+
+    /// Only media created at or after this time, i.e. 2020-01-31T00:00:00Z
+    #[structopt(long = "from")]
+    from: Option<DateTime<Utc>>,
+
+    /// Only media created before this time
+    #[structopt(long = "to")]
+    to: Option<DateTime<Utc>>,
+
+    /// Only media taken with the given camera model, ignoring case
+    #[structopt(long = "camera-model")]
+    camera_model: Option<String>,
+}
+
+impl FilterArgs {
+    pub fn into_filter(self) -> Result<EntityFilter> {
+        Ok(EntityFilter {
+            ids: self.ids,
+            media_type: self.media_type,
+            tag: self.tag.map(|t| Tag::canonical_name(&t)).transpose()?,
+            created_from: self.from,
+            created_to: self.to,
+            camera_model: self.camera_model,
+            ..Default::default()
+        })
+    }
+}
+
 #[derive(Debug, StructOpt)]
 pub enum SubCmdTag {
     /// Add a new tag to the db, on the format: name type Option(parent)
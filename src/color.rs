@@ -0,0 +1,304 @@
+/*
+Image database, allows the user to host a database themselves,
+with the possibilities to tag and search after images.
+Copyright (C) 2020 Cybercom group AB, Sweden
+By Christoffer Dahl, Johanna Hultberg, Andreas Runfalk and Margareta Vi
+
+Image database is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use exif::{Exif, In, Tag, Value};
+use flate2::read::ZlibDecoder;
+use image::{DynamicImage, GenericImageView, ImageBuffer};
+use lcms2::{
+    CIExyY, CIExyYTRIPLE, ColorSpaceSignature, Intent, PixelFormat, Profile, ToneCurve, Transform,
+};
+use libheif_rs::HeifContext;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use crate::thumbnail::FileType;
+
+/// EXIF ColorSpace value for anything that isn't sRGB
+const COLOR_SPACE_UNCALIBRATED: u32 = 0xffff;
+
+/// TIFF tag holding an ICC profile
+const TAG_ICC_PROFILE: u16 = 34675;
+
+/// The colour space of an image, when it's something else than sRGB
+#[derive(Clone, Debug, PartialEq)]
+pub enum ColorProfile {
+    Icc(Vec<u8>),
+    /// Cameras set to Adobe RGB often say so in EXIF only, without embedding a profile
+    AdobeRgb,
+}
+
+/// Find the colour space of an image. `None` means sRGB, which is what all derivatives use
+pub fn read_color_profile<P: AsRef<Path>>(path: P, file_type: &FileType) -> Option<ColorProfile> {
+    let path = path.as_ref();
+    if file_type.is_heif() {
+        let handle = HeifContext::read_from_file(path.to_str()?)
+            .ok()?
+            .primary_image_handle()
+            .ok()?;
+        return handle
+            .color_profile_raw()
+            .map(|profile| ColorProfile::Icc(profile.data));
+    }
+
+    let data = fs::read(path).ok()?;
+    let icc = match file_type {
+        FileType::Jpeg => jpeg_icc(&data),
+        FileType::Png => png_icc(&data),
+        FileType::Webp => webp_icc(&data),
+        _ => None,
+    };
+    icc.map(ColorProfile::Icc).or_else(|| {
+        let exif = exif::Reader::new()
+            .read_from_container(&mut Cursor::new(&data))
+            .ok()?;
+        exif_color_profile(&exif)
+    })
+}
+
+/// Find the colour space of JPEG data, such as the previews embedded in raw files
+pub fn jpeg_color_profile(data: &[u8]) -> Option<ColorProfile> {
+    jpeg_icc(data).map(ColorProfile::Icc).or_else(|| {
+        let exif = exif::Reader::new()
+            .read_from_container(&mut Cursor::new(data))
+            .ok()?;
+        exif_color_profile(&exif)
+    })
+}
+
+/// TIFF files keep their ICC profile in a tag, everything else only has the ColorSpace tag
+pub fn exif_color_profile(exif: &Exif) -> Option<ColorProfile> {
+    if let Some(field) = exif.get_field(Tag(exif::Context::Tiff, TAG_ICC_PROFILE), In::PRIMARY) {
+        match field.value {
+            Value::Undefined(ref data, _) | Value::Byte(ref data) => {
+                return Some(ColorProfile::Icc(data.clone()))
+            }
+            _ => {}
+        }
+    }
+
+    // DCF says that uncalibrated together with the R03 interoperability index is Adobe RGB
+    let color_space = exif
+        .get_field(Tag::ColorSpace, In::PRIMARY)?
+        .value
+        .get_uint(0)?;
+    let adobe_rgb = match exif.get_field(Tag::InteroperabilityIndex, In::PRIMARY) {
+        Some(field) => match field.value {
+            Value::Ascii(ref values) => values.iter().any(|v| v.starts_with(b"R03")),
+            _ => false,
+        },
+        None => false,
+    };
+    if color_space == COLOR_SPACE_UNCALIBRATED && adobe_rgb {
+        Some(ColorProfile::AdobeRgb)
+    } else {
+        None
+    }
+}
+
+/// Returns the marker and contents of each segment before the image data of a JPEG file
+pub fn jpeg_segments(data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut segments = Vec::new();
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xff {
+        let marker = data[pos + 1];
+        match marker {
+            // Padding
+            0xff => {
+                pos += 1;
+                continue;
+            }
+            // Start of scan, end of image
+            0xda | 0xd9 => break,
+            _ => {}
+        }
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let segment = match data.get(pos + 4..pos + 2 + len) {
+            Some(segment) => segment,
+            None => break,
+        };
+        segments.push((marker, segment));
+        pos += 2 + len;
+    }
+    segments
+}
+
+/// ICC profiles are split over one or more APP2 segments before the image data
+fn jpeg_icc(data: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks: Vec<(u8, &[u8])> = jpeg_segments(data)
+        .into_iter()
+        .filter(|(marker, segment)| {
+            *marker == 0xe2 && segment.starts_with(b"ICC_PROFILE\0") && segment.len() > 14
+        })
+        .map(|(_, segment)| (segment[12], &segment[14..]))
+        .collect();
+
+    if chunks.is_empty() {
+        return None;
+    }
+    chunks.sort_by_key(|(sequence, _)| *sequence);
+    Some(
+        chunks
+            .into_iter()
+            .flat_map(|(_, chunk)| chunk)
+            .copied()
+            .collect(),
+    )
+}
+
+/// The profile is stored compressed in the iCCP chunk
+fn png_icc(data: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 8;
+    while pos + 8 <= data.len() {
+        let len = u32::from_be_bytes(data[pos..pos + 4].try_into().ok()?) as usize;
+        let chunk_type = &data[pos + 4..pos + 8];
+        let chunk = data.get(pos + 8..pos + 8 + len)?;
+        match chunk_type {
+            b"iCCP" => {
+                // The profile name comes first, followed by the compression method
+                let name_len = chunk.iter().position(|&b| b == 0)?;
+                let mut icc = Vec::new();
+                ZlibDecoder::new(chunk.get(name_len + 2..)?)
+                    .read_to_end(&mut icc)
+                    .ok()?;
+                return Some(icc);
+            }
+            b"IDAT" => return None,
+            _ => {}
+        }
+        // Length, type, data and CRC
+        pos += 12 + len;
+    }
+    None
+}
+
+/// Extended WebP files may have an ICCP chunk
+fn webp_icc(data: &[u8]) -> Option<Vec<u8>> {
+    if data.get(0..4)? != b"RIFF" || data.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let fourcc = &data[pos..pos + 4];
+        let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().ok()?) as usize;
+        if fourcc == b"ICCP" {
+            return data.get(pos + 8..pos + 8 + len).map(|icc| icc.to_vec());
+        }
+        // Chunks are padded to an even size
+        pos += 8 + len + (len & 1);
+    }
+    None
+}
+
+fn adobe_rgb_profile() -> Result<Profile> {
+    let d65 = CIExyY {
+        x: 0.3127,
+        y: 0.3290,
+        Y: 1.0,
+    };
+    let primaries = CIExyYTRIPLE {
+        Red: CIExyY {
+            x: 0.64,
+            y: 0.33,
+            Y: 1.0,
+        },
+        Green: CIExyY {
+            x: 0.21,
+            y: 0.71,
+            Y: 1.0,
+        },
+        Blue: CIExyY {
+            x: 0.15,
+            y: 0.06,
+            Y: 1.0,
+        },
+    };
+    let gamma = ToneCurve::new(563.0 / 256.0);
+    Ok(Profile::new_rgb(
+        &d65,
+        &primaries,
+        &[&gamma, &gamma, &gamma],
+    )?)
+}
+
+/// Convert the image to sRGB, as browsers assume that images without a profile are sRGB and
+/// derivatives don't carry one. Images that can't be converted, such as grayscale or CMYK ones or
+/// those with a broken profile, are returned as they are
+pub fn convert_to_srgb(img: DynamicImage, profile: &ColorProfile) -> DynamicImage {
+    match try_convert_to_srgb(&img, profile) {
+        Ok(Some(converted)) => converted,
+        Ok(None) => img,
+        Err(err) => {
+            println!(
+                "Failed to convert to sRGB, keeping the original colours: {}",
+                err
+            );
+            img
+        }
+    }
+}
+
+/// Returns `None` when the profile isn't an RGB one
+fn try_convert_to_srgb(img: &DynamicImage, profile: &ColorProfile) -> Result<Option<DynamicImage>> {
+    let source = match profile {
+        ColorProfile::Icc(data) => Profile::new_icc(data)?,
+        ColorProfile::AdobeRgb => adobe_rgb_profile()?,
+    };
+    if source.color_space() != ColorSpaceSignature::RgbData {
+        return Ok(None);
+    }
+    let transform: Transform<[u8; 3], [u8; 3]> = Transform::new(
+        &source,
+        PixelFormat::RGB_8,
+        &Profile::new_srgb(),
+        PixelFormat::RGB_8,
+        Intent::Perceptual,
+    )?;
+
+    // Only the colour channels are converted, alpha is put back afterwards
+    let rgba = img.to_rgba();
+    let mut pixels: Vec<[u8; 3]> = rgba.pixels().map(|p| [p[0], p[1], p[2]]).collect();
+    transform.transform_in_place(&mut pixels);
+    let (width, height) = img.dimensions();
+    let invalid = || anyhow!("Failed to convert image to sRGB");
+    let converted = if has_alpha(img) {
+        let mut data = Vec::with_capacity(pixels.len() * 4);
+        for (rgb, pixel) in pixels.iter().zip(rgba.pixels()) {
+            data.extend_from_slice(rgb);
+            data.push(pixel[3]);
+        }
+        DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, data).ok_or_else(invalid)?)
+    } else {
+        DynamicImage::ImageRgb8(
+            ImageBuffer::from_raw(width, height, pixels.concat()).ok_or_else(invalid)?,
+        )
+    };
+    Ok(Some(converted))
+}
+
+fn has_alpha(img: &DynamicImage) -> bool {
+    match img {
+        DynamicImage::ImageLumaA8(_)
+        | DynamicImage::ImageRgba8(_)
+        | DynamicImage::ImageBgra8(_) => true,
+        _ => false,
+    }
+}
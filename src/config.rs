@@ -18,6 +18,10 @@ You should have received a copy of the GNU General Public License
 along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 use serde::Deserialize;
+use std::iter::once;
+use std::path::{Path, PathBuf};
+
+use crate::storage::DEST_DIR;
 
 fn db_default_host() -> String {
     "/var/run/postgresql/".into()
@@ -52,10 +56,235 @@ pub struct Database {
     pub dbname: String,
 }
 
+fn import_default_workers() -> usize {
+    num_cpus::get()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Import {
+    /// Number of files that are hashed and thumbnailed concurrently
+    #[serde(default = "import_default_workers")]
+    pub workers: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RawSource {
+    /// Fully develop the raw data, which is slow but always works
+    Develop,
+    /// Use the full size JPEG preview embedded by the camera, falling back to developing the raw
+    /// data if there is none
+    Embedded,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Fill the whole box, cropping around the largest face if there is one
+    Crop,
+    /// Fit within the box keeping the aspect ratio. Images are never scaled up
+    Contain,
+}
+
+impl Fit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Fit::Crop => "crop",
+            Fit::Contain => "contain",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Avif => "avif",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "jpg" => Some(OutputFormat::Jpeg),
+            "png" => Some(OutputFormat::Png),
+            "webp" => Some(OutputFormat::Webp),
+            "avif" => Some(OutputFormat::Avif),
+            _ => None,
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Avif => "image/avif",
+        }
+    }
+}
+
+fn profile_default_quality() -> u8 {
+    75
+}
+
+/// A size that is generated for every image and video
+#[derive(Clone, Debug, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    #[serde(default)]
+    pub fit: Fit,
+    #[serde(default)]
+    pub format: OutputFormat,
+    /// Only used by lossy formats, from 1 to 100
+    #[serde(default = "profile_default_quality")]
+    pub quality: u8,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Derivatives {
+    /// What thumbnails and previews of raw images are made from
+    #[serde(default)]
+    pub raw_source: RawSource,
+    #[serde(default, rename = "profile")]
+    profiles: Vec<Profile>,
+    /// Every profile is also saved in these formats, and served to browsers that support them
+    #[serde(default)]
+    pub extra_formats: Vec<OutputFormat>,
+}
+
+impl Derivatives {
+    pub const THUMBNAIL: &'static str = "thumbnail";
+    pub const PREVIEW: &'static str = "preview";
+
+    /// All profiles to generate. The thumbnail and preview profiles are always included and use
+    /// their default sizes unless they are configured
+    pub fn profiles(&self) -> Vec<Profile> {
+        let defaults = vec![
+            Profile {
+                name: Self::THUMBNAIL.into(),
+                width: 300,
+                height: 200,
+                fit: Fit::Crop,
+                format: OutputFormat::default(),
+                quality: profile_default_quality(),
+            },
+            Profile {
+                name: Self::PREVIEW.into(),
+                width: 4096,
+                height: 2160,
+                fit: Fit::Contain,
+                format: OutputFormat::default(),
+                quality: profile_default_quality(),
+            },
+        ];
+        let mut profiles: Vec<Profile> = defaults
+            .into_iter()
+            .filter(|default| !self.profiles.iter().any(|p| p.name == default.name))
+            .collect();
+        profiles.extend(self.profiles.iter().cloned());
+        profiles
+    }
+}
+
+fn render_default_cache_dir() -> PathBuf {
+    "cache".into()
+}
+
+fn render_default_cache_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Render {
+    /// Where images rendered on request are kept
+    #[serde(default = "render_default_cache_dir")]
+    pub cache_dir: PathBuf,
+    /// Maximum size of the cache in bytes, the least recently used images are removed first
+    #[serde(default = "render_default_cache_size")]
+    pub cache_size: u64,
+    #[serde(default = "profile_default_quality")]
+    pub quality: u8,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LibraryMode {
+    /// Originals are copied into the managed storage
+    Copy,
+    /// Originals are left where they are and only thumbnails and previews are generated
+    Reference,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Library {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub mode: LibraryMode,
+}
+
 #[derive(Clone, Debug, Default, Deserialize)]
 pub struct Config {
     #[serde(default)]
     pub database: Database,
+    #[serde(default)]
+    pub import: Import,
+    #[serde(default)]
+    pub derivatives: Derivatives,
+    #[serde(default)]
+    pub render: Render,
+    #[serde(default, rename = "library")]
+    pub libraries: Vec<Library>,
+}
+
+impl Config {
+    /// Returns the library the given file belongs to, if any
+    pub fn library_for<P: AsRef<Path>>(&self, path: P) -> Option<&Library> {
+        let path = path.as_ref().canonicalize().ok()?;
+        self.libraries.iter().find(|library| {
+            library
+                .path
+                .canonicalize()
+                .map(|root| path.starts_with(root))
+                .unwrap_or(false)
+        })
+    }
+
+    pub fn is_referenced<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.library_for(path)
+            .map(|library| library.mode == LibraryMode::Reference)
+            .unwrap_or(false)
+    }
+
+    /// Returns true if the path is within the managed storage or a referenced library. Anything
+    /// else must never be served, even if an entity happens to point at it
+    pub fn is_servable<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = match path.as_ref().canonicalize() {
+            Ok(path) => path,
+            Err(_) => return false,
+        };
+        let referenced = self
+            .libraries
+            .iter()
+            .filter(|library| library.mode == LibraryMode::Reference)
+            .map(|library| library.path.clone());
+        once(PathBuf::from(DEST_DIR))
+            .chain(referenced)
+            .filter_map(|root| root.canonicalize().ok())
+            .any(|root| path.starts_with(root))
+    }
 }
 
 impl Default for Database {
@@ -68,3 +297,45 @@ impl Default for Database {
         }
     }
 }
+
+impl Default for Import {
+    fn default() -> Self {
+        Self {
+            workers: import_default_workers(),
+        }
+    }
+}
+
+impl Default for Render {
+    fn default() -> Self {
+        Self {
+            cache_dir: render_default_cache_dir(),
+            cache_size: render_default_cache_size(),
+            quality: profile_default_quality(),
+        }
+    }
+}
+
+impl Default for RawSource {
+    fn default() -> Self {
+        RawSource::Develop
+    }
+}
+
+impl Default for Fit {
+    fn default() -> Self {
+        Fit::Contain
+    }
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Jpeg
+    }
+}
+
+impl Default for LibraryMode {
+    fn default() -> Self {
+        LibraryMode::Copy
+    }
+}
@@ -0,0 +1,387 @@
+/*
+Image database, allows the user to host a database themselves,
+with the possibilities to tag and search after images.
+Copyright (C) 2020 Cybercom group AB, Sweden
+By Christoffer Dahl, Johanna Hultberg, Andreas Runfalk and Margareta Vi
+
+Image database is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use exif::{Exif, Field, In, Tag, Value};
+use roxmltree::{Document, Node};
+use serde_json::{Map, Value as SerdeValue};
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+use crate::color::jpeg_segments;
+use crate::metadata::read_exif;
+use crate::thumbnail::{file_type_from_path, FileType, MediaType};
+
+const RDF_NS: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+
+/// TIFF tags that are dumped in their own section or are of no use as text
+const TAG_XMP: u16 = 700;
+const TAG_IPTC: u16 = 33723;
+const TAG_ICC_PROFILE: u16 = 34675;
+
+/// Binary values larger than this are left out, they are usually vendor specific blobs
+const MAX_BINARY_SIZE: usize = 1024;
+
+/// Names of the IPTC application record datasets
+const IPTC_DATASETS: &[(u8, &str)] = &[
+    (5, "ObjectName"),
+    (7, "EditStatus"),
+    (10, "Urgency"),
+    (12, "SubjectReference"),
+    (15, "Category"),
+    (20, "SupplementalCategories"),
+    (22, "FixtureIdentifier"),
+    (25, "Keywords"),
+    (26, "ContentLocationCode"),
+    (27, "ContentLocationName"),
+    (30, "ReleaseDate"),
+    (35, "ReleaseTime"),
+    (37, "ExpirationDate"),
+    (38, "ExpirationTime"),
+    (40, "SpecialInstructions"),
+    (45, "ReferenceService"),
+    (47, "ReferenceDate"),
+    (50, "ReferenceNumber"),
+    (55, "DateCreated"),
+    (60, "TimeCreated"),
+    (62, "DigitalCreationDate"),
+    (63, "DigitalCreationTime"),
+    (65, "OriginatingProgram"),
+    (70, "ProgramVersion"),
+    (75, "ObjectCycle"),
+    (80, "By-line"),
+    (85, "By-lineTitle"),
+    (90, "City"),
+    (92, "Sub-location"),
+    (95, "Province-State"),
+    (100, "Country-PrimaryLocationCode"),
+    (101, "Country-PrimaryLocationName"),
+    (103, "OriginalTransmissionReference"),
+    (105, "Headline"),
+    (110, "Credit"),
+    (115, "Source"),
+    (116, "CopyrightNotice"),
+    (118, "Contact"),
+    (120, "Caption-Abstract"),
+    (122, "Writer-Editor"),
+];
+
+/// Datasets that may be repeated, they are always arrays
+const IPTC_REPEATABLE: &[u8] = &[12, 20, 25, 26, 27, 80, 85, 118, 122];
+
+/// Read every EXIF, XMP and IPTC field of an image. The result is an object with one object per
+/// kind of metadata found, i.e. `{"exif": {"Make": "Canon", ...}, "xmp": {"xmp:Rating": "5"}}`.
+/// Videos are not read, as they rarely have any of these
+pub fn dump_metadata<P: AsRef<Path>>(path: P) -> Result<SerdeValue> {
+    let path = path.as_ref();
+    let file_type = file_type_from_path(path).ok_or(anyhow!("Unknown file type"))?;
+    let mut dump = Map::new();
+    if file_type.media_type() == MediaType::Video {
+        return Ok(SerdeValue::Object(dump));
+    }
+
+    let data = fs::read(path)?;
+    let exif = read_exif(path).ok();
+    if let Some(ref exif) = exif {
+        let fields = exif_fields(exif);
+        if !fields.is_empty() {
+            dump.insert("exif".into(), SerdeValue::Object(fields));
+        }
+    }
+
+    if let Some(xmp) = find_xmp(&data).and_then(|xmp| parse_xmp(xmp).ok()) {
+        if !xmp.is_empty() {
+            dump.insert("xmp".into(), SerdeValue::Object(xmp));
+        }
+    }
+
+    let iim = match file_type {
+        FileType::Jpeg => jpeg_iptc(&data),
+        _ => exif.as_ref().and_then(tiff_iptc),
+    };
+    if let Some(iptc) = iim.map(|iim| parse_iim(&iim)) {
+        if !iptc.is_empty() {
+            dump.insert("iptc".into(), SerdeValue::Object(iptc));
+        }
+    }
+
+    let mut dump = SerdeValue::Object(dump);
+    remove_nul(&mut dump);
+    Ok(dump)
+}
+
+/// Writers often NUL terminate strings, or pad them with NUL, which Postgres doesn't allow in
+/// JSON
+fn remove_nul(value: &mut SerdeValue) {
+    match value {
+        SerdeValue::String(text) if text.contains('\0') => *text = text.replace('\0', ""),
+        SerdeValue::Array(values) => values.iter_mut().for_each(remove_nul),
+        SerdeValue::Object(map) => {
+            let keys: Vec<String> = map.keys().filter(|k| k.contains('\0')).cloned().collect();
+            for key in keys {
+                if let Some(value) = map.remove(&key) {
+                    map.insert(key.replace('\0', ""), value);
+                }
+            }
+            map.values_mut().for_each(remove_nul);
+        }
+        _ => {}
+    }
+}
+
+fn exif_fields(exif: &Exif) -> Map<String, SerdeValue> {
+    let mut fields = Map::new();
+    for field in exif.fields() {
+        // The thumbnail IFD describes the embedded thumbnail, not the image
+        if field.ifd_num != In::PRIMARY || is_skipped_tag(field.tag) {
+            continue;
+        }
+        if let Some(value) = exif_value(exif, field) {
+            fields.insert(field.tag.to_string(), value);
+        }
+    }
+    fields
+}
+
+fn is_skipped_tag(tag: Tag) -> bool {
+    tag == Tag::MakerNote
+        || [TAG_XMP, TAG_IPTC, TAG_ICC_PROFILE]
+            .iter()
+            .any(|&number| tag == Tag(exif::Context::Tiff, number))
+}
+
+fn exif_value(exif: &Exif, field: &Field) -> Option<SerdeValue> {
+    match field.value {
+        Value::Ascii(ref values) => {
+            let mut strings: Vec<SerdeValue> = values
+                .iter()
+                .map(|v| {
+                    let text = String::from_utf8_lossy(v);
+                    SerdeValue::String(text.trim_end_matches(|c| c == '\0' || c == ' ').into())
+                })
+                .collect();
+            if strings.len() == 1 {
+                strings.pop()
+            } else {
+                Some(SerdeValue::Array(strings))
+            }
+        }
+        Value::Undefined(ref data, _) | Value::Byte(ref data) if data.len() > MAX_BINARY_SIZE => {
+            None
+        }
+        _ => Some(SerdeValue::String(
+            field.display_value().with_unit(exif).to_string(),
+        )),
+    }
+}
+
+/// XMP packets are stored as plain text in every format that supports them, so instead of
+/// knowing where each format keeps it the file is searched
+fn find_xmp(data: &[u8]) -> Option<&str> {
+    let find = |needle: &[u8], from: usize| {
+        data.get(from..)?
+            .windows(needle.len())
+            .position(|window| window == needle)
+            .map(|pos| pos + from)
+    };
+    let start = find(b"<x:xmpmeta", 0)?;
+    let end_tag = b"</x:xmpmeta>";
+    let end = find(end_tag, start)? + end_tag.len();
+    std::str::from_utf8(&data[start..end]).ok()
+}
+
+/// Flatten the properties of all `rdf:Description` elements into one object, keyed by
+/// `prefix:name`
+fn parse_xmp(xmp: &str) -> Result<Map<String, SerdeValue>> {
+    let doc = Document::parse(xmp)?;
+    let mut properties = Map::new();
+    for description in doc.descendants().filter(|node| {
+        node.is_element()
+            && node.tag_name().namespace() == Some(RDF_NS)
+            && node.tag_name().name() == "Description"
+            && node
+                .parent_element()
+                .map_or(false, |parent| parent.tag_name().name() == "RDF")
+    }) {
+        xmp_properties(description, &mut properties);
+    }
+    Ok(properties)
+}
+
+fn xmp_name(node: Node, namespace: Option<&str>, name: &str) -> String {
+    match namespace.and_then(|ns| node.lookup_prefix(ns)) {
+        Some(prefix) => format!("{}:{}", prefix, name),
+        None => name.to_string(),
+    }
+}
+
+/// Properties may be written both as attributes and as child elements
+fn xmp_properties(node: Node, properties: &mut Map<String, SerdeValue>) {
+    for attribute in node.attributes() {
+        if attribute.namespace() == Some(RDF_NS) || attribute.namespace().is_none() {
+            continue;
+        }
+        properties.insert(
+            xmp_name(node, attribute.namespace(), attribute.name()),
+            SerdeValue::String(attribute.value().to_string()),
+        );
+    }
+    for child in node.children().filter(Node::is_element) {
+        let name = xmp_name(child, child.tag_name().namespace(), child.tag_name().name());
+        properties.insert(name, xmp_value(child));
+    }
+}
+
+fn xmp_value(node: Node) -> SerdeValue {
+    if let Some(resource) = node.attribute((RDF_NS, "resource")) {
+        return SerdeValue::String(resource.to_string());
+    }
+
+    let children: Vec<Node> = node.children().filter(Node::is_element).collect();
+    match children.first() {
+        Some(child) if child.tag_name().namespace() == Some(RDF_NS) => {
+            match child.tag_name().name() {
+                // Ordered, unordered and alternative lists
+                "Seq" | "Bag" | "Alt" => SerdeValue::Array(
+                    child
+                        .children()
+                        .filter(|li| li.is_element() && li.tag_name().name() == "li")
+                        .map(xmp_value)
+                        .collect(),
+                ),
+                _ => {
+                    let mut structure = Map::new();
+                    xmp_properties(*child, &mut structure);
+                    SerdeValue::Object(structure)
+                }
+            }
+        }
+        Some(_) => {
+            let mut structure = Map::new();
+            xmp_properties(node, &mut structure);
+            SerdeValue::Object(structure)
+        }
+        None if node
+            .attributes()
+            .iter()
+            .any(|a| a.namespace().is_some() && a.namespace() != Some(RDF_NS)) =>
+        {
+            // A structure written with attributes only
+            let mut structure = Map::new();
+            xmp_properties(node, &mut structure);
+            SerdeValue::Object(structure)
+        }
+        None => SerdeValue::String(node.text().unwrap_or("").trim().to_string()),
+    }
+}
+
+/// IPTC is stored as a Photoshop image resource in an APP13 segment
+fn jpeg_iptc(data: &[u8]) -> Option<Vec<u8>> {
+    let segment = jpeg_segments(data)
+        .into_iter()
+        .find(|(marker, segment)| *marker == 0xed && segment.starts_with(b"Photoshop 3.0\0"))?
+        .1;
+
+    let mut pos = 14;
+    while pos + 12 <= segment.len() && &segment[pos..pos + 4] == b"8BIM" {
+        let id = u16::from_be_bytes(segment[pos + 4..pos + 6].try_into().ok()?);
+        // The name is a Pascal string padded to an even size, including the length byte
+        let name_len = *segment.get(pos + 6)? as usize;
+        pos += 6 + ((name_len + 2) & !1);
+        let size = u32::from_be_bytes(segment.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        pos += 4;
+        let resource = segment.get(pos..pos + size)?;
+        if id == 0x0404 {
+            return Some(resource.to_vec());
+        }
+        pos += (size + 1) & !1;
+    }
+    None
+}
+
+/// TIFF files, and so most raw files, keep IPTC in a tag
+fn tiff_iptc(exif: &Exif) -> Option<Vec<u8>> {
+    match exif
+        .get_field(Tag(exif::Context::Tiff, TAG_IPTC), In::PRIMARY)?
+        .value
+    {
+        Value::Undefined(ref data, _) | Value::Byte(ref data) => Some(data.clone()),
+        _ => None,
+    }
+}
+
+/// Parse the IPTC-IIM application record. Known datasets get their names, others are named by
+/// number, i.e. `2:200`
+fn parse_iim(data: &[u8]) -> Map<String, SerdeValue> {
+    let mut datasets = Map::new();
+    let mut pos = 0;
+    while pos + 5 <= data.len() && data[pos] == 0x1c {
+        let record = data[pos + 1];
+        let dataset = data[pos + 2];
+        let mut len = u16::from_be_bytes([data[pos + 3], data[pos + 4]]) as usize;
+        pos += 5;
+        if len & 0x8000 != 0 {
+            // Extended dataset, the length is stored in the following bytes
+            let len_size = len & 0x7fff;
+            len = match data.get(pos..pos + len_size) {
+                Some(bytes) if len_size <= 8 => bytes
+                    .iter()
+                    .fold(0usize, |len, &byte| (len << 8) | byte as usize),
+                _ => break,
+            };
+            pos += len_size;
+        }
+        let value = match pos.checked_add(len).and_then(|end| data.get(pos..end)) {
+            Some(value) => value,
+            None => break,
+        };
+        pos += len;
+
+        // Only the application record describes the image, and its version is of no interest
+        if record != 2 || dataset == 0 {
+            continue;
+        }
+        let name = IPTC_DATASETS
+            .iter()
+            .find(|(number, _)| *number == dataset)
+            .map(|(_, name)| name.to_string())
+            .unwrap_or_else(|| format!("2:{}", dataset));
+        let value = SerdeValue::String(
+            String::from_utf8_lossy(value)
+                .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+                .to_string(),
+        );
+        match datasets.get_mut(&name) {
+            Some(SerdeValue::Array(values)) => values.push(value),
+            Some(existing) => {
+                // Repeated even though it shouldn't be
+                let first = existing.take();
+                *existing = SerdeValue::Array(vec![first, value]);
+            }
+            None if IPTC_REPEATABLE.contains(&dataset) => {
+                datasets.insert(name, SerdeValue::Array(vec![value]));
+            }
+            None => {
+                datasets.insert(name, value);
+            }
+        }
+    }
+    datasets
+}
@@ -0,0 +1,246 @@
+/*
+Image database, allows the user to host a database themselves,
+with the possibilities to tag and search after images.
+Copyright (C) 2020 Cybercom group AB, Sweden
+By Christoffer Dahl, Johanna Hultberg, Andreas Runfalk and Margareta Vi
+
+Image database is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+
+use crate::hash::Sha3;
+use crate::metadata::Rotate;
+use crate::thumbnail::rotate_image;
+
+/// Gamma used to approximate the sRGB curve when adjusting light
+const GAMMA: f32 = 2.2;
+
+/// How much a white balance of -1 or 1 scales the red and blue channels
+const WHITE_BALANCE_STRENGTH: f32 = 0.3;
+
+/// A single non-destructive edit. Edits are stored in order per entity and applied whenever
+/// thumbnails, previews and renders are made, the original is never touched
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum EditOperation {
+    /// Keep part of the image. All values are fractions of the image size, so the crop still
+    /// fits when the image is opened at a different size
+    Crop {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+    /// Rotate by a small angle, clockwise, and crop away the corners that end up empty
+    Straighten { degrees: f32 },
+    /// Rotate by quarter turns clockwise
+    Rotate { turns: i32 },
+    /// Change the exposure by this many stops
+    Exposure { ev: f32 },
+    /// Between -100 and 100
+    Contrast { amount: f32 },
+    /// Both between -1 and 1. Positive temperature is warmer, positive tint is more magenta
+    WhiteBalance { temperature: f32, tint: f32 },
+}
+
+impl EditOperation {
+    pub fn validate(&self) -> Result<()> {
+        let in_range =
+            |value: f32, min: f32, max: f32| value.is_finite() && value >= min && value <= max;
+        let valid = match *self {
+            EditOperation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                in_range(x, 0.0, 1.0)
+                    && in_range(y, 0.0, 1.0)
+                    && width > 0.0
+                    && height > 0.0
+                    && in_range(x + width, 0.0, 1.0)
+                    && in_range(y + height, 0.0, 1.0)
+            }
+            EditOperation::Straighten { degrees } => in_range(degrees, -45.0, 45.0),
+            EditOperation::Rotate { .. } => true,
+            EditOperation::Exposure { ev } => in_range(ev, -5.0, 5.0),
+            EditOperation::Contrast { amount } => in_range(amount, -100.0, 100.0),
+            EditOperation::WhiteBalance { temperature, tint } => {
+                in_range(temperature, -1.0, 1.0) && in_range(tint, -1.0, 1.0)
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(anyhow!("Invalid edit {:?}", self))
+        }
+    }
+}
+
+/// Exposure and white balance for raw files, which are set on the raw pipeline instead of being
+/// applied to the developed 8-bit image
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RawAdjustments {
+    pub exposure: f32,
+    pub temperature: f32,
+    pub tint: f32,
+}
+
+impl RawAdjustments {
+    pub fn is_neutral(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// Split off the edits that the raw pipeline can do. The remaining edits are returned in order
+pub fn split_raw_adjustments(edits: &[EditOperation]) -> (RawAdjustments, Vec<EditOperation>) {
+    let mut adjustments = RawAdjustments::default();
+    let mut rest = Vec::new();
+    for edit in edits {
+        match *edit {
+            EditOperation::Exposure { ev } => adjustments.exposure += ev,
+            EditOperation::WhiteBalance { temperature, tint } => {
+                adjustments.temperature += temperature;
+                adjustments.tint += tint;
+            }
+            _ => rest.push(edit.clone()),
+        }
+    }
+    (adjustments, rest)
+}
+
+/// Multipliers for the red, green and blue channels
+pub fn white_balance_multipliers(temperature: f32, tint: f32) -> [f32; 3] {
+    [
+        1.0 + temperature * WHITE_BALANCE_STRENGTH,
+        1.0 - tint * WHITE_BALANCE_STRENGTH,
+        1.0 - temperature * WHITE_BALANCE_STRENGTH,
+    ]
+}
+
+/// A short string that changes whenever the edits do, used to tell renders apart
+pub fn fingerprint(edits: &[EditOperation]) -> Result<Option<String>> {
+    if edits.is_empty() {
+        return Ok(None);
+    }
+    let sha3 = Sha3::from_bytes(serde_json::to_string(edits)?.as_bytes());
+    Ok(Some(sha3.to_string()[..16].to_string()))
+}
+
+pub fn apply_edits(img: DynamicImage, edits: &[EditOperation]) -> Result<DynamicImage> {
+    edits.iter().try_fold(img, apply_edit)
+}
+
+fn apply_edit(img: DynamicImage, edit: &EditOperation) -> Result<DynamicImage> {
+    edit.validate()?;
+    Ok(match *edit {
+        EditOperation::Crop {
+            x,
+            y,
+            width,
+            height,
+        } => {
+            let (img_width, img_height) = img.dimensions();
+            let to_pixels = |fraction: f32, size: u32| (fraction * size as f32).round() as u32;
+            let left = to_pixels(x, img_width).min(img_width - 1);
+            let top = to_pixels(y, img_height).min(img_height - 1);
+            let mut img = img;
+            img.crop(
+                left,
+                top,
+                to_pixels(width, img_width).max(1).min(img_width - left),
+                to_pixels(height, img_height).max(1).min(img_height - top),
+            )
+        }
+        EditOperation::Straighten { degrees } => {
+            DynamicImage::ImageRgb8(straighten(&img.to_rgb(), degrees))
+        }
+        EditOperation::Rotate { turns } => {
+            let rotation = match turns.rem_euclid(4) {
+                0 => Rotate::Zero,
+                1 => Rotate::Cw90,
+                2 => Rotate::Cw180,
+                _ => Rotate::Ccw90,
+            };
+            rotate_image(&img, rotation)?
+        }
+        EditOperation::Exposure { ev } => {
+            let gain = 2f32.powf(ev);
+            map_linear(&img, [gain, gain, gain])
+        }
+        EditOperation::Contrast { amount } => img.adjust_contrast(amount),
+        EditOperation::WhiteBalance { temperature, tint } => {
+            map_linear(&img, white_balance_multipliers(temperature, tint))
+        }
+    })
+}
+
+/// Multiply each channel by the given factor in linear light
+fn map_linear(img: &DynamicImage, factors: [f32; 3]) -> DynamicImage {
+    let mut luts = [[0u8; 256]; 3];
+    for (lut, factor) in luts.iter_mut().zip(factors.iter()) {
+        for (value, out) in lut.iter_mut().enumerate() {
+            let linear = (value as f32 / 255.0).powf(GAMMA) * factor;
+            *out = (linear.min(1.0).powf(1.0 / GAMMA) * 255.0).round() as u8;
+        }
+    }
+
+    let mut rgb = img.to_rgb();
+    for pixel in rgb.pixels_mut() {
+        for (channel, lut) in pixel.0.iter_mut().zip(luts.iter()) {
+            *channel = lut[*channel as usize];
+        }
+    }
+    DynamicImage::ImageRgb8(rgb)
+}
+
+/// Rotate around the center and keep the largest part with the same aspect ratio that has no
+/// empty corners
+fn straighten(img: &RgbImage, degrees: f32) -> RgbImage {
+    let (width, height) = img.dimensions();
+    let (w, h) = (width as f32, height as f32);
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let scale = (w / (w * cos.abs() + h * sin.abs())).min(h / (w * sin.abs() + h * cos.abs()));
+    let out_width = ((w * scale).floor() as u32).max(1);
+    let out_height = ((h * scale).floor() as u32).max(1);
+
+    let sample = |x: f32, y: f32| -> Rgb<u8> {
+        // Bilinear interpolation between the four closest pixels
+        let x = x.max(0.0).min(w - 1.0);
+        let y = y.max(0.0).min(h - 1.0);
+        let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+        let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+        let mut out = [0u8; 3];
+        for (i, value) in out.iter_mut().enumerate() {
+            let top = img.get_pixel(x0, y0).0[i] as f32 * (1.0 - fx)
+                + img.get_pixel(x1, y0).0[i] as f32 * fx;
+            let bottom = img.get_pixel(x0, y1).0[i] as f32 * (1.0 - fx)
+                + img.get_pixel(x1, y1).0[i] as f32 * fx;
+            *value = (top * (1.0 - fy) + bottom * fy).round() as u8;
+        }
+        Rgb(out)
+    };
+
+    ImageBuffer::from_fn(out_width, out_height, |x, y| {
+        let dx = x as f32 + 0.5 - out_width as f32 / 2.0;
+        let dy = y as f32 + 0.5 - out_height as f32 / 2.0;
+        sample(
+            w / 2.0 + dx * cos + dy * sin - 0.5,
+            h / 2.0 - dx * sin + dy * cos - 0.5,
+        )
+    })
+}
@@ -22,13 +22,15 @@ use async_std::io::{Read as AsyncRead, ReadExt};
 use sha3::digest::Digest;
 use std::convert::TryInto;
 use std::fmt;
+use std::fs::File;
+use std::io::Read;
 use std::path::Path;
 
 use crate::error::Result;
 
 type HashType = [u8; 32];
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Sha3 {
     hash: HashType,
 }
@@ -62,6 +64,26 @@ impl Sha3 {
 
         Ok(hasher.result().into())
     }
+
+    pub fn from_bytes(data: &[u8]) -> Self {
+        sha3::Sha3_256::digest(data).into()
+    }
+
+    /// Blocking version of `from_path`, meant to be run on a worker thread
+    pub fn from_path_blocking<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut file = File::open(path.as_ref())?;
+        let mut buf = [0u8; 4096];
+        let mut hasher = sha3::Sha3_256::new();
+        loop {
+            let buf_len = file.read(&mut buf)?;
+            if buf_len == 0 {
+                break;
+            }
+            hasher.input(&buf[..buf_len]);
+        }
+
+        Ok(hasher.result().into())
+    }
 }
 
 impl<T: Into<HashType>> From<T> for Sha3 {
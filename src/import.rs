@@ -0,0 +1,324 @@
+/*
+Image database, allows the user to host a database themselves,
+with the possibilities to tag and search after images.
+Copyright (C) 2020 Cybercom group AB, Sweden
+By Christoffer Dahl, Johanna Hultberg, Andreas Runfalk and Margareta Vi
+
+Image database is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::lock::Mutex;
+use futures::stream::{self, StreamExt};
+use serde_json::Value as SerdeValue;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use tokio_postgres::Client;
+use walkdir::WalkDir;
+
+use crate::config::{Config, Derivatives};
+use crate::coord::Location;
+use crate::dump::dump_metadata;
+use crate::error::Result;
+use crate::hash::Sha3;
+use crate::metadata::Metadata;
+use crate::model::{Derivative, Entity, EntityMetadata, EntityType, RawMetadata};
+use crate::storage::{original_name, DerivativeFile};
+use crate::thumbnail::{
+    copy_and_create_thumbnail, create_thumbnail_in_place, file_type_from_path, remove_stored_files,
+    warn_on_extension_mismatch,
+};
+use crate::util::{get_media_type, modified_time, run_blocking};
+
+#[derive(Debug)]
+pub enum ImportOutcome {
+    Imported(i32),
+    /// The file is already imported
+    Duplicate(i32),
+    Unsupported,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub imported: Vec<(PathBuf, i32)>,
+    pub duplicates: Vec<(PathBuf, i32)>,
+    pub unsupported: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl ImportSummary {
+    pub fn is_empty(&self) -> bool {
+        self.imported.is_empty()
+            && self.duplicates.is_empty()
+            && self.unsupported.is_empty()
+            && self.failed.is_empty()
+    }
+
+    pub fn record(&mut self, path: PathBuf, outcome: Result<ImportOutcome>) {
+        match outcome {
+            Ok(ImportOutcome::Imported(id)) => self.imported.push((path, id)),
+            Ok(ImportOutcome::Duplicate(id)) => self.duplicates.push((path, id)),
+            Ok(ImportOutcome::Unsupported) => self.unsupported.push(path),
+            Err(err) => self.failed.push((path, err.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Imported: {}", self.imported.len())?;
+        writeln!(f, "Skipped duplicates: {}", self.duplicates.len())?;
+        writeln!(f, "Ignored unsupported files: {}", self.unsupported.len())?;
+        write!(f, "Failed: {}", self.failed.len())?;
+        for (path, reason) in self.failed.iter() {
+            write!(f, "\n  {:?}: {}", path, reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything about a file that is computed on the worker pool before it's inserted
+struct PreparedFile {
+    media_type: EntityType,
+    path: PathBuf,
+    thumbnail_path: PathBuf,
+    preview_path: PathBuf,
+    size: u64,
+    created: Option<DateTime<Utc>>,
+    created_local: Option<NaiveDateTime>,
+    utc_offset: Option<i32>,
+    location: Option<Location>,
+    original_name: String,
+    derivatives: Vec<DerivativeFile>,
+    blurhash: Option<String>,
+    modified: Option<DateTime<Utc>>,
+    metadata: Option<Metadata>,
+    raw_metadata: Option<SerdeValue>,
+}
+
+fn prepare_file(
+    path: &Path,
+    sha3: &Sha3,
+    referenced: bool,
+    options: &Derivatives,
+) -> Result<PreparedFile> {
+    let size = path.metadata()?.len();
+    let storage = if referenced {
+        create_thumbnail_in_place(path, sha3, options, None, &[])?
+    } else {
+        copy_and_create_thumbnail(path, sha3, options)?
+    };
+
+    let metadata = Metadata::from_file(path).ok();
+    let created = metadata.as_ref().and_then(|m| m.date_time);
+    let created_local = metadata.as_ref().and_then(|m| m.local_date_time);
+    let utc_offset = metadata.as_ref().and_then(|m| m.utc_offset);
+    let location = metadata.as_ref().and_then(|m| m.gps_location.clone());
+
+    Ok(PreparedFile {
+        media_type: get_media_type(path)?,
+        path: storage.original,
+        thumbnail_path: storage.thumbnail,
+        preview_path: storage.preview,
+        size,
+        created,
+        created_local,
+        utc_offset,
+        location,
+        original_name: original_name(path),
+        derivatives: storage.derivatives,
+        blurhash: storage.blurhash,
+        modified: modified_time(path),
+        metadata,
+        raw_metadata: dump_metadata(path).ok(),
+    })
+}
+
+async fn import_claimed_file(
+    client: &Client,
+    config: &Config,
+    state: &ImportState,
+    path: &Path,
+    sha3: &Sha3,
+) -> Result<ImportOutcome> {
+    if let Some(e) = Entity::get_from_sha3(client, sha3).await {
+        return Ok(ImportOutcome::Duplicate(e.id));
+    }
+
+    let src_path = path.to_path_buf();
+    let src_sha3 = sha3.clone();
+    let referenced = config.is_referenced(path);
+    let options = config.derivatives.clone();
+    let prepared =
+        run_blocking(move || prepare_file(&src_path, &src_sha3, referenced, &options)).await?;
+
+    // Every import uses the same connection, so only one of them may be in a transaction
+    let write = state.write.lock().await;
+    let inserted = insert_in_transaction(client, path, sha3, &prepared).await;
+    drop(write);
+    match inserted {
+        Ok(id) => Ok(ImportOutcome::Imported(id)),
+        Err(err) => {
+            // Nothing refers to the files made for this hash, as it wasn't imported before
+            let original = if referenced {
+                None
+            } else {
+                Some(prepared.path.as_path())
+            };
+            remove_stored_files(sha3, &config.derivatives, original);
+            Err(err)
+        }
+    }
+}
+
+async fn insert_in_transaction(
+    client: &Client,
+    path: &Path,
+    sha3: &Sha3,
+    prepared: &PreparedFile,
+) -> Result<i32> {
+    client.batch_execute("BEGIN").await?;
+    match insert_prepared_file(client, sha3, prepared).await {
+        Ok(id) => {
+            client.batch_execute("COMMIT").await?;
+            Ok(id)
+        }
+        Err(err) => {
+            // Report why the insert failed rather than why the rollback did
+            if let Err(rollback_err) = client.batch_execute("ROLLBACK").await {
+                println!("Failed to roll back import of {:?}: {}", path, rollback_err);
+            }
+            Err(err)
+        }
+    }
+}
+
+async fn insert_prepared_file(
+    client: &Client,
+    sha3: &Sha3,
+    prepared: &PreparedFile,
+) -> Result<i32> {
+    let entity = Entity::insert(
+        client,
+        prepared.media_type,
+        &prepared.path,
+        &prepared.thumbnail_path,
+        &prepared.preview_path,
+        prepared.size,
+        sha3,
+        &prepared.created,
+        &prepared.created_local,
+        &prepared.utc_offset,
+        &prepared.location,
+        &prepared.original_name,
+        &prepared.blurhash,
+        &prepared.modified,
+    )
+    .await?;
+    Derivative::replace_for_eid(client, entity.id, &prepared.derivatives).await?;
+    if let Some(ref metadata) = prepared.metadata {
+        EntityMetadata::set(client, entity.id, metadata).await?;
+    }
+    if let Some(ref raw_metadata) = prepared.raw_metadata {
+        RawMetadata::set(client, entity.id, raw_metadata).await?;
+    }
+    Ok(entity.id)
+}
+
+/// State shared between all imports running at the same time on one connection
+#[derive(Default)]
+pub struct ImportState {
+    /// Hashes currently being imported. Whoever holds the lock of a hash imports it, others wait
+    /// and then find it in the database, or try themselves if the import failed
+    in_flight: RefCell<HashMap<Sha3, Rc<Mutex<()>>>>,
+    /// Held while inserting a file
+    write: Mutex<()>,
+}
+
+/// Import a single file. `state` must be shared between all concurrent calls
+pub async fn import_file(
+    client: &Client,
+    config: &Config,
+    state: &ImportState,
+    path: &Path,
+) -> Result<ImportOutcome> {
+    match file_type_from_path(path) {
+        Some(file_type) => warn_on_extension_mismatch(path, &file_type),
+        None => return Ok(ImportOutcome::Unsupported),
+    }
+
+    let hash_path = path.to_path_buf();
+    let sha3 = run_blocking(move || Sha3::from_path_blocking(&hash_path)).await?;
+
+    // Claim the hash before checking the database. This way two identical files that are
+    // imported at the same time can't both pass the duplicate check
+    let claim = state
+        .in_flight
+        .borrow_mut()
+        .entry(sha3.clone())
+        .or_default()
+        .clone();
+    let outcome = {
+        let _claimed = claim.lock().await;
+        import_claimed_file(client, config, state, path, &sha3).await
+    };
+    // Only the map and this call hold the claim when nobody else is waiting for it
+    if Rc::strong_count(&claim) == 2 {
+        state.in_flight.borrow_mut().remove(&sha3);
+    }
+    outcome
+}
+
+pub async fn populate_database(
+    client: &Client,
+    config: &Config,
+    src_dirs: &[PathBuf],
+    workers: usize,
+) -> Result<ImportSummary> {
+    let state = ImportState::default();
+    let state = &state;
+
+    let entries = src_dirs
+        .iter()
+        .map(|src_dir| WalkDir::new(src_dir).follow_links(true))
+        .flatten()
+        .filter(|entry| match entry {
+            Ok(entry) => !entry.file_type().is_dir(),
+            Err(_) => true,
+        });
+
+    let mut results = stream::iter(entries)
+        .map(|entry| async move {
+            match entry {
+                Ok(entry) => {
+                    let path = entry.into_path();
+                    let outcome = import_file(client, config, state, &path).await;
+                    (path, outcome)
+                }
+                Err(err) => (
+                    err.path().map(Path::to_path_buf).unwrap_or_default(),
+                    Err(err.into()),
+                ),
+            }
+        })
+        .buffer_unordered(workers.max(1));
+
+    let mut summary = ImportSummary::default();
+    while let Some((path, outcome)) = results.next().await {
+        summary.record(path, outcome);
+    }
+    Ok(summary)
+}
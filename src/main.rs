@@ -17,98 +17,54 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
-use std::path::PathBuf;
+use anyhow::anyhow;
+use std::time::Duration;
 use tokio_postgres::Client;
-use walkdir::WalkDir;
 
 mod api;
 mod cli;
+mod color;
 mod config;
 mod coord;
+mod dump;
+mod edit;
 mod error;
 mod face_detection;
 mod hash;
+mod import;
 mod metadata;
 mod model;
+mod raw;
+mod regenerate;
+mod render;
+mod rescan;
+mod storage;
 mod tags;
 mod thumbnail;
+mod time_correction;
 mod util;
+mod verify;
+mod watch;
 mod web;
 
 use crate::cli::{Args, Cmd, SubCmdTag};
-use crate::config::Config;
+use crate::config::{Config, OutputFormat};
+use crate::dump::dump_metadata;
 use crate::error::Result;
-use crate::hash::Sha3;
+use crate::import::populate_database;
 use crate::metadata::Metadata;
-use crate::model::{create_schema, Entity, Tag};
+use crate::model::{create_schema, Edit, Entity, Tag};
+use crate::regenerate::{backfill_placeholders, refresh_metadata, regenerate, set_rotation};
+use crate::render::open_edited_original;
+use crate::rescan::{apply_changes, rescan};
 use crate::tags::{add_parent, list_tags, remove_parent, search_tags, tag_image};
-use crate::thumbnail::{copy_and_create_thumbnail, file_type_from_path};
-use crate::util::{get_db, get_media_type};
+use crate::thumbnail::save_image;
+use crate::time_correction::{correct_times, undo_time_correction, TimeChange};
+use crate::util::{get_db, run_blocking};
+use crate::verify::{repair, verify};
+use crate::watch::watch_directories;
 use crate::web::run_server;
 
-async fn populate_database(client: &Client, src_dirs: &Vec<PathBuf>) -> Result<()> {
-    for path in src_dirs
-        .iter()
-        .map(|src_dir| WalkDir::new(src_dir).follow_links(true))
-        .flatten()
-    {
-        let path = path?.into_path();
-        if file_type_from_path(&path).is_none() {
-            println!("Ignoring {:?}", path);
-            continue;
-        }
-
-        let file_metadata = match path.metadata() {
-            Ok(m) => m,
-            Err(err) => {
-                println!("Failed to stat file: {}", err);
-                continue;
-            }
-        };
-
-        // Calculate SHA-3 to see if the file is already imported
-        let sha3 = Sha3::from_path(&path).await?;
-        if let Some(e) = Entity::get_from_sha3(&client, &sha3).await {
-            println!("{:?} is already imported (id {})", path, e.id);
-            continue;
-        }
-
-        println!("Making thumbnail for {:?}", &path);
-        let (img, thumbnail, preview) = match copy_and_create_thumbnail(&path) {
-            Ok((i, t, p)) => (i, t, p),
-            Err(err) => {
-                println!("Failed: {}", err);
-                continue;
-            }
-        };
-
-        let mut created = None;
-        let mut location = None;
-
-        if let Ok(metadata) = Metadata::from_file(&path) {
-            created = metadata.date_time;
-            location = metadata.gps_location;
-        }
-
-        let media_type = get_media_type(&path)?;
-
-        let entity = Entity::insert(
-            &client,
-            media_type,
-            &img,
-            &thumbnail,
-            &preview,
-            file_metadata.len(),
-            &sha3,
-            &created,
-            &location,
-        )
-        .await?;
-        dbg!(entity);
-    }
-    Ok(())
-}
-
 async fn delete_image(client: &Client, id: String) -> Result<()> {
     let new_id: i32 = id.parse()?;
     Entity::delete(&client, new_id).await?;
@@ -131,15 +87,152 @@ async fn main() -> Result<()> {
         Cmd::Run => {
             run_server(config).await?;
         }
-        Cmd::Import { paths } => {
-            populate_database(&get_db(config).await?, &paths).await?;
+        Cmd::Import { paths, jobs } => {
+            let workers = jobs.unwrap_or(config.import.workers);
+            let client = get_db(config.clone()).await?;
+            let summary = populate_database(&client, &config, &paths, workers).await?;
+            println!("{}", summary);
+        }
+        Cmd::Watch { paths, jobs, delay } => {
+            let workers = jobs.unwrap_or(config.import.workers);
+            watch_directories(
+                &get_db(config.clone()).await?,
+                &config,
+                &paths,
+                workers,
+                Duration::from_secs(delay),
+            )
+            .await?;
+        }
+        Cmd::Rescan { paths, apply, jobs } => {
+            let workers = jobs.unwrap_or(config.import.workers);
+            let roots = if paths.is_empty() {
+                config.libraries.iter().map(|l| l.path.clone()).collect()
+            } else {
+                paths
+            };
+            let client = get_db(config.clone()).await?;
+            let changes = rescan(&client, &config, &roots, workers).await?;
+            for change in changes.iter() {
+                println!("{}", change);
+            }
+            if changes.is_empty() {
+                println!("Everything is up to date");
+            } else if apply {
+                apply_changes(&client, &config, changes, workers).await?;
+            } else {
+                println!("Run again with --apply to update the database");
+            }
+        }
+        Cmd::Verify {
+            regenerate,
+            delete_orphans,
+            jobs,
+        } => {
+            let workers = jobs.unwrap_or(config.import.workers);
+            let client = get_db(config.clone()).await?;
+            let problems = verify(&client, workers).await?;
+            for problem in problems.iter() {
+                println!("{}", problem);
+            }
+            println!("Found {} problems", problems.len());
+            repair(
+                &client,
+                &config,
+                &problems,
+                regenerate,
+                delete_orphans,
+                workers,
+            )
+            .await?;
+        }
+        Cmd::Regenerate { filter, jobs } => {
+            let workers = jobs.unwrap_or(config.import.workers);
+            let filter = filter.into_filter()?;
+            let client = get_db(config.clone()).await?;
+            println!("{}", regenerate(&client, &config, &filter, workers).await?);
+        }
+        Cmd::RefreshMetadata { filter, jobs } => {
+            let workers = jobs.unwrap_or(config.import.workers);
+            let filter = filter.into_filter()?;
+            let client = get_db(config.clone()).await?;
+            println!("{}", refresh_metadata(&client, &filter, workers).await?);
+        }
+        Cmd::CorrectTime {
+            filter,
+            shift,
+            set,
+            apply,
+            write_sidecars,
+        } => {
+            let change = match (shift, set) {
+                (Some(shift), _) => TimeChange::parse_shift(&shift)?,
+                (None, Some(set)) => TimeChange::parse_set(&set)?,
+                (None, None) => return Err(anyhow!("Either --shift or --set is required").into()),
+            };
+            let filter = filter.into_filter()?;
+            let client = get_db(config.clone()).await?;
+            let summary =
+                correct_times(&client, &config, &filter, &change, apply, write_sidecars).await?;
+            println!("{}", summary);
+            match summary.id {
+                Some(id) => println!("Run undo-correct-time {} to put the old times back", id),
+                None if !summary.changes.is_empty() => {
+                    println!("Run again with --apply to change the capture times")
+                }
+                None => {}
+            }
+        }
+        Cmd::UndoCorrectTime { id } => {
+            let client = get_db(config.clone()).await?;
+            println!("{}", undo_time_correction(&client, &config, id).await?);
+        }
+        Cmd::Placeholders { all, jobs } => {
+            let workers = jobs.unwrap_or(config.import.workers);
+            let client = get_db(config.clone()).await?;
+            println!("{}", backfill_placeholders(&client, all, workers).await?);
+        }
+        Cmd::Rotate {
+            id,
+            rotation,
+            reset,
+        } => {
+            let rotation = if reset { None } else { rotation };
+            let client = get_db(config.clone()).await?;
+            set_rotation(&client, &config, id, rotation).await?;
+            println!("Rotated {}", id);
+        }
+        Cmd::Export { id, path, quality } => {
+            let format = path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(|ext| OutputFormat::from_extension(&ext.to_ascii_lowercase()))
+                .ok_or(anyhow!("Unsupported export format {:?}", path))?;
+            let quality = quality.unwrap_or(config.render.quality);
+            let client = get_db(config.clone()).await?;
+            let entity = Entity::get(&client, id)
+                .await
+                .ok_or(anyhow!("No such media {}", id))?;
+            let edits = Edit::operations_for(&client, id).await?;
+            let raw_source = config.derivatives.raw_source.clone();
+            let export_path = path.clone();
+            run_blocking(move || {
+                let img = open_edited_original(&entity, &edits, &raw_source)?;
+                Ok(save_image(&img, &export_path, format, quality)?)
+            })
+            .await?;
+            println!("Exported {} to {:?}", id, path);
         }
         Cmd::InitDb => {
             create_schema(&get_db(config.clone()).await?).await?;
             Tag::insert(&get_db(config.clone()).await?, "Places", None).await?;
             Tag::insert(&get_db(config.clone()).await?, "People", None).await?;
         }
-        Cmd::Metadata { path } => match Metadata::from_file(&path) {
+        Cmd::Metadata { path, raw: true } => match dump_metadata(&path) {
+            Ok(dump) => println!("{}", serde_json::to_string_pretty(&dump)?),
+            Err(err) => println!("Unable to read metadata of {:?}: {}", &path, err),
+        },
+        Cmd::Metadata { path, raw: false } => match Metadata::from_file(&path) {
             Ok(metadata) => println!("{:#?}", metadata),
             Err(_) => println!(
                 "Unable to get metadata for {:?}, maybe it's unsupported",
@@ -18,18 +18,22 @@ You should have received a copy of the GNU General Public License
 along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 use anyhow::{anyhow, Result};
-use chrono::NaiveDateTime;
 use chrono::{DateTime, Utc};
-use exif::{In, Reader, Tag, Value};
+use chrono::{Duration, NaiveDate, NaiveDateTime, Timelike};
+use exif::{Exif, In, Reader, Tag, Value};
 use fraction::prelude::Fraction;
+use image::GenericImageView;
 use serde_json::{json, Value as SerdeValue};
 use std::convert::TryInto;
 use std::fs;
 use std::path::Path;
 use std::process::{Command, Output};
+use std::str::FromStr;
 
 use crate::coord::{DecDegrees, Location};
-use crate::thumbnail::{file_type_from_path, find_orientation, MediaType};
+use crate::thumbnail::{
+    file_type_from_path, find_orientation, heif_dimensions, open_image, FileType, MediaType,
+};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Rotate {
@@ -39,22 +43,176 @@ pub enum Rotate {
     Cw180,
 }
 
+impl Rotate {
+    /// Degrees clockwise
+    pub fn degrees(&self) -> i16 {
+        match self {
+            Rotate::Zero => 0,
+            Rotate::Cw90 => 90,
+            Rotate::Cw180 => 180,
+            Rotate::Ccw90 => 270,
+        }
+    }
+
+    pub fn from_degrees(degrees: i16) -> Option<Self> {
+        match degrees.rem_euclid(360) {
+            0 => Some(Rotate::Zero),
+            90 => Some(Rotate::Cw90),
+            180 => Some(Rotate::Cw180),
+            270 => Some(Rotate::Ccw90),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Rotate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.parse()
+            .ok()
+            .and_then(Rotate::from_degrees)
+            .ok_or(anyhow!("Rotation must be 0, 90, 180 or 270 degrees"))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Metadata {
     pub width: u32,
     pub height: u32,
+    /// When the media was captured. If the offset to UTC isn't known the wall-clock time is
+    /// assumed to be UTC
     pub date_time: Option<DateTime<Utc>>,
+    /// Wall-clock time where the media was captured
+    pub local_date_time: Option<NaiveDateTime>,
+    /// Seconds east of UTC of the local time, `None` when unknown
+    pub utc_offset: Option<i32>,
     pub gps_location: Option<Location>,
     pub rotation: Option<Rotate>,
     pub type_specific: TypeSpecific,
 }
 
+/// The EXIF ExposureProgram values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExposureProgram {
+    Manual,
+    Normal,
+    AperturePriority,
+    ShutterPriority,
+    Creative,
+    Action,
+    Portrait,
+    Landscape,
+}
+
+impl ExposureProgram {
+    pub fn from_exif(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(ExposureProgram::Manual),
+            2 => Some(ExposureProgram::Normal),
+            3 => Some(ExposureProgram::AperturePriority),
+            4 => Some(ExposureProgram::ShutterPriority),
+            5 => Some(ExposureProgram::Creative),
+            6 => Some(ExposureProgram::Action),
+            7 => Some(ExposureProgram::Portrait),
+            8 => Some(ExposureProgram::Landscape),
+            _ => None,
+        }
+    }
+
+    pub fn to_exif(self) -> u32 {
+        match self {
+            ExposureProgram::Manual => 1,
+            ExposureProgram::Normal => 2,
+            ExposureProgram::AperturePriority => 3,
+            ExposureProgram::ShutterPriority => 4,
+            ExposureProgram::Creative => 5,
+            ExposureProgram::Action => 6,
+            ExposureProgram::Portrait => 7,
+            ExposureProgram::Landscape => 8,
+        }
+    }
+}
+
+/// The EXIF MeteringMode values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MeteringMode {
+    Average,
+    CenterWeighted,
+    Spot,
+    MultiSpot,
+    Pattern,
+    Partial,
+    Other,
+}
+
+impl MeteringMode {
+    pub fn from_exif(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(MeteringMode::Average),
+            2 => Some(MeteringMode::CenterWeighted),
+            3 => Some(MeteringMode::Spot),
+            4 => Some(MeteringMode::MultiSpot),
+            5 => Some(MeteringMode::Pattern),
+            6 => Some(MeteringMode::Partial),
+            255 => Some(MeteringMode::Other),
+            _ => None,
+        }
+    }
+
+    pub fn to_exif(self) -> u32 {
+        match self {
+            MeteringMode::Average => 1,
+            MeteringMode::CenterWeighted => 2,
+            MeteringMode::Spot => 3,
+            MeteringMode::MultiSpot => 4,
+            MeteringMode::Pattern => 5,
+            MeteringMode::Partial => 6,
+            MeteringMode::Other => 255,
+        }
+    }
+}
+
+/// The EXIF WhiteBalance values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WhiteBalance {
+    Auto,
+    Manual,
+}
+
+impl WhiteBalance {
+    pub fn from_exif(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(WhiteBalance::Auto),
+            1 => Some(WhiteBalance::Manual),
+            _ => None,
+        }
+    }
+
+    pub fn to_exif(self) -> u32 {
+        match self {
+            WhiteBalance::Auto => 0,
+            WhiteBalance::Manual => 1,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct ImageMetadata {
     pub exposure_time: Option<Fraction>,
     pub aperture: Option<f32>,
     pub iso: Option<u32>,
     pub flash: Option<bool>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens_model: Option<String>,
+    /// In millimetres
+    pub focal_length: Option<f32>,
+    /// What the focal length would be on a 35mm camera with the same field of view
+    pub focal_length_35mm: Option<f32>,
+    pub exposure_program: Option<ExposureProgram>,
+    pub metering_mode: Option<MeteringMode>,
+    pub white_balance: Option<WhiteBalance>,
 }
 #[derive(Clone, Debug, Default)]
 pub struct VideoMetadata {
@@ -84,6 +242,9 @@ impl Metadata {
                     let simple_metadata = simple_metadata_from_raw_image(&path)?;
                     metadata.width = simple_metadata.width;
                     metadata.height = simple_metadata.height;
+                    if metadata.rotation.is_none() {
+                        metadata.rotation = simple_metadata.rotation;
+                    }
                 }
 
                 metadata
@@ -133,31 +294,56 @@ fn map_rotation(rot: &str) -> Result<Rotate> {
     }
 }
 
-// the gps format is +58.3938+015.5612/
+/// Parse an ISO 6709 location such as `+58.3938+015.5612/` or `-33.8688+151.2093+025.000/`
 fn gps_video(coord: &str) -> Result<Location> {
-    let coord = coord.replace("/", "");
-    let split: Vec<String> = coord.split("+").map(|s| s.to_string()).collect();
-    let lat = split[1].parse::<f64>()?;
-    let lon = split[2].parse::<f64>()?;
+    let coord = coord.trim().trim_end_matches('/');
+    let mut starts = coord
+        .match_indices(|c| c == '+' || c == '-')
+        .map(|(i, _)| i);
+    let lat_start = starts
+        .next()
+        .ok_or(anyhow!("Missing latitude in {}", coord))?;
+    let lon_start = starts
+        .next()
+        .ok_or(anyhow!("Missing longitude in {}", coord))?;
+    let lon_end = starts.next().unwrap_or(coord.len());
+    let lat = coord[lat_start..lon_start].parse::<f64>()?;
+    let lon = coord[lon_start..lon_end].parse::<f64>()?;
     let place = Location::reverse_geolocation(lat, lon);
     Ok(Location::new(lat, lon, place))
 }
 
-fn field_as_string(reader: &Reader, tag: Tag) -> Option<String> {
-    Some(
-        reader
-            .get_field(tag, In::PRIMARY)?
-            .value
-            .display_as(tag)
-            .to_string(),
-    )
+fn field_as_uint(reader: &Exif, tag: Tag) -> Option<u32> {
+    reader.get_field(tag, In::PRIMARY)?.value.get_uint(0)
+}
+
+/// Text fields are often padded with spaces or NUL characters
+fn field_as_ascii(reader: &Exif, tag: Tag) -> Option<String> {
+    match reader.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(ref values) => {
+            let text = String::from_utf8_lossy(values.first()?);
+            let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            if text.is_empty() {
+                None
+            } else {
+                Some(text.to_string())
+            }
+        }
+        _ => None,
+    }
 }
 
-fn field_as_uint(reader: &Reader, tag: Tag) -> Option<u32> {
-    reader.get_field(tag, In::PRIMARY)?.value.get_uint(0)
+fn field_as_f32(reader: &Exif, tag: Tag) -> Option<f32> {
+    match reader.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(ref values) => values
+            .first()
+            .filter(|v| v.denom != 0)
+            .map(|v| v.to_f64() as f32),
+        ref value => value.get_uint(0).map(|v| v as f32),
+    }
 }
 
-fn width_and_height(reader: &Reader) -> Result<(u32, u32)> {
+fn width_and_height(reader: &Exif) -> Result<(u32, u32)> {
     let mut width = field_as_uint(&reader, Tag::ImageLength);
     let mut height = field_as_uint(&reader, Tag::ImageWidth);
 
@@ -172,7 +358,7 @@ fn width_and_height(reader: &Reader) -> Result<(u32, u32)> {
     }
 }
 
-fn exposure_time(reader: &Reader) -> Option<Fraction> {
+fn exposure_time(reader: &Exif) -> Option<Fraction> {
     let field = &reader.get_field(Tag::ExposureTime, In::PRIMARY)?.value;
     if let Value::Rational(exp_times) = field {
         // Note: this will panic if no exposure times present
@@ -182,7 +368,7 @@ fn exposure_time(reader: &Reader) -> Option<Fraction> {
     }
 }
 
-fn aperture(reader: &Reader) -> Option<f32> {
+fn aperture(reader: &Exif) -> Option<f32> {
     if let Some(field) = &reader.get_field(Tag::ApertureValue, In::PRIMARY) {
         if let Value::Rational(ref ap) = field.value {
             // Note: this will panic if no aperture val present
@@ -200,7 +386,7 @@ fn aperture(reader: &Reader) -> Option<f32> {
     None
 }
 
-fn flash(reader: &Reader) -> Option<bool> {
+fn flash(reader: &Exif) -> Option<bool> {
     let flash = field_as_uint(&reader, Tag::Flash)?;
     let flash_fired = vec![
         0x1, 0x5, 0x7, 0x9, 0xd, 0xf, 0x19, 0x1d, 0x1f, 0x41, 0x45, 0x47, 0x49, 0x4d, 0x4f, 0x59,
@@ -213,7 +399,38 @@ fn flash(reader: &Reader) -> Option<bool> {
     }
 }
 
-fn gps_image(reader: &Reader) -> Option<Location> {
+/// Diagonal of a 35mm film frame in millimetres
+const FULL_FRAME_DIAGONAL: f32 = 43.27;
+
+/// Use the tag when the camera writes it, otherwise compute it from the size of the sensor
+fn focal_length_35mm(reader: &Exif, focal_length: Option<f32>) -> Option<f32> {
+    match field_as_uint(reader, Tag::FocalLengthIn35mmFilm) {
+        Some(value) if value > 0 => return Some(value as f32),
+        _ => {}
+    }
+
+    let focal_length = focal_length?;
+    let mm_per_unit = match field_as_uint(reader, Tag::FocalPlaneResolutionUnit).unwrap_or(2) {
+        3 => 10.0,
+        4 => 1.0,
+        5 => 0.001,
+        _ => 25.4,
+    };
+    let sensor_size = |pixels: Tag, resolution: Tag| -> Option<f32> {
+        let resolution = field_as_f32(reader, resolution).filter(|r| *r > 0.0)?;
+        Some(field_as_uint(reader, pixels)? as f32 / resolution * mm_per_unit)
+    };
+    let width = sensor_size(Tag::PixelXDimension, Tag::FocalPlaneXResolution)?;
+    let height = sensor_size(Tag::PixelYDimension, Tag::FocalPlaneYResolution)?;
+    let diagonal = (width * width + height * height).sqrt();
+    if diagonal > 0.0 {
+        Some((focal_length * FULL_FRAME_DIAGONAL / diagonal).round())
+    } else {
+        None
+    }
+}
+
+fn gps_image(reader: &Exif) -> Option<Location> {
     let lat_field = &reader.get_field(Tag::GPSLatitude, In::PRIMARY)?.value;
     let lon_field = &reader.get_field(Tag::GPSLongitude, In::PRIMARY)?.value;
     if let (Value::Rational(lat_dms), Value::Rational(lon_dms)) = (lat_field, lon_field) {
@@ -245,11 +462,18 @@ fn gps_image(reader: &Reader) -> Option<Location> {
 }
 
 pub fn simple_metadata_from_image<P: AsRef<Path>>(path: P) -> Result<Metadata> {
-    let (width, height) = image::image_dimensions(path.as_ref())?;
+    let file_type = file_type_from_path(&path).ok_or(anyhow!("Unknown file type"))?;
+    let (width, height) = if file_type.is_heif() {
+        heif_dimensions(&path)?
+    } else {
+        open_image(&path, &file_type)?.dimensions()
+    };
     return Ok(Metadata {
         width,
         height,
         date_time: None,
+        local_date_time: None,
+        utc_offset: None,
         gps_location: None,
         rotation: None,
         type_specific: TypeSpecific::Image(Default::default()),
@@ -257,29 +481,217 @@ pub fn simple_metadata_from_image<P: AsRef<Path>>(path: P) -> Result<Metadata> {
 }
 
 pub fn simple_metadata_from_raw_image<P: AsRef<Path>>(path: P) -> Result<Metadata> {
-    let rawloader::RawImage { width, height, .. } = rawloader::decode_file(path.as_ref())?;
+    let rawloader::RawImage {
+        width,
+        height,
+        orientation,
+        ..
+    } = rawloader::decode_file(path.as_ref())?;
+    let rotation = match orientation {
+        rawloader::Orientation::Normal => Some(Rotate::Zero),
+        rawloader::Orientation::Rotate90 => Some(Rotate::Cw90),
+        rawloader::Orientation::Rotate180 => Some(Rotate::Cw180),
+        rawloader::Orientation::Rotate270 => Some(Rotate::Ccw90),
+        _ => None,
+    };
     return Ok(Metadata {
         width: width.try_into()?,
         height: height.try_into()?,
         date_time: None,
+        local_date_time: None,
+        utc_offset: None,
         gps_location: None,
-        rotation: None,
+        rotation,
         type_specific: TypeSpecific::Image(Default::default()),
     });
 }
 
-pub fn extract_exif_metadata<P: AsRef<Path>>(path: P) -> Result<Metadata> {
-    let file = fs::File::open(path.as_ref())?;
-    let reader = Reader::new(&mut std::io::BufReader::new(&file))?;
-
-    let date_time = field_as_string(&reader, Tag::DateTime);
-    let date_time = date_time
-        .and_then(|date_time| NaiveDateTime::parse_from_str(&date_time, "%Y-%m-%d %H:%M:%S").ok());
-    let date_time = if let Some(v) = date_time {
-        Some(DateTime::<Utc>::from_utc(v, Utc))
+fn be_u32(data: &[u8], offset: usize) -> Option<usize> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+}
+
+/// Read the EXIF block of a file. Most formats are handled by the container reader, but some raw
+/// formats need special treatment
+pub fn read_exif<P: AsRef<Path>>(path: P) -> Result<Exif> {
+    match file_type_from_path(&path) {
+        Some(FileType::Orf) | Some(FileType::Rw2) => {
+            // These are TIFF files with a custom magic number, so we patch in the standard one
+            let mut data = fs::read(&path)?;
+            if data.len() < 4 {
+                return Err(anyhow!("File is too short to contain EXIF"));
+            }
+            if data[0] == b'I' {
+                data[2] = 0x2a;
+                data[3] = 0x00;
+            } else {
+                data[2] = 0x00;
+                data[3] = 0x2a;
+            }
+            Ok(Reader::new().read_raw(data)?)
+        }
+        Some(FileType::Raf) => {
+            // The EXIF is stored in an embedded JPEG whose offset and length are in the header
+            let data = fs::read(&path)?;
+            let jpeg = be_u32(&data, 84)
+                .and_then(|offset| Some((offset, be_u32(&data, 88)?)))
+                .and_then(|(offset, len)| data.get(offset..offset + len))
+                .ok_or(anyhow!("Unable to find embedded JPEG in RAF"))?;
+            Ok(Reader::new().read_from_container(&mut std::io::Cursor::new(jpeg))?)
+        }
+        _ => {
+            let file = fs::File::open(path.as_ref())?;
+            Ok(Reader::new().read_from_container(&mut std::io::BufReader::new(&file))?)
+        }
+    }
+}
+
+/// When something was captured, as both UTC and the wall-clock time where it happened
+struct CaptureTime {
+    utc: DateTime<Utc>,
+    local: Option<NaiveDateTime>,
+    offset: Option<i32>,
+}
+
+impl CaptureTime {
+    /// Only the point in time is known, not the local time
+    fn utc(utc: DateTime<Utc>) -> Self {
+        Self {
+            utc,
+            local: None,
+            offset: None,
+        }
+    }
+
+    /// Without an offset the local time is assumed to be UTC
+    fn local(local: NaiveDateTime, offset: Option<i32>) -> Self {
+        let utc = local - Duration::seconds(offset.unwrap_or(0).into());
+        Self {
+            utc: DateTime::<Utc>::from_utc(utc, Utc),
+            local: Some(local),
+            offset,
+        }
+    }
+}
+
+/// Tags holding the capture time in order of preference, together with the tags holding their
+/// fractions of a second and offset to UTC. DateTime is when the file was last changed, so it's
+/// only used when there is nothing else
+const EXIF_DATE_TAGS: &[(Tag, Tag, Tag)] = &[
+    (
+        Tag::DateTimeOriginal,
+        Tag::SubSecTimeOriginal,
+        Tag::OffsetTimeOriginal,
+    ),
+    (
+        Tag::DateTimeDigitized,
+        Tag::SubSecTimeDigitized,
+        Tag::OffsetTimeDigitized,
+    ),
+    (Tag::DateTime, Tag::SubSecTime, Tag::OffsetTime),
+];
+
+/// Offsets that differ from this by more are not trusted
+const MAX_UTC_OFFSET: i64 = 14 * 3600;
+
+fn exif_capture_time(reader: &Exif) -> Option<CaptureTime> {
+    let (local, sub_sec_tag, offset_tag) =
+        EXIF_DATE_TAGS
+            .iter()
+            .find_map(|&(date_time_tag, sub_sec_tag, offset_tag)| {
+                let date_time = field_as_ascii(reader, date_time_tag)?;
+                Some((parse_exif_date_time(&date_time)?, sub_sec_tag, offset_tag))
+            })?;
+    let local = field_as_ascii(reader, sub_sec_tag)
+        .and_then(|sub_sec| parse_sub_sec(&sub_sec))
+        .and_then(|nanos| local.with_nanosecond(nanos))
+        .unwrap_or(local);
+    let offset = field_as_ascii(reader, offset_tag)
+        .and_then(|offset| parse_utc_offset(&offset))
+        .or_else(|| gps_utc_offset(reader, &local));
+    Some(CaptureTime::local(local, offset))
+}
+
+fn parse_exif_date_time(date_time: &str) -> Option<NaiveDateTime> {
+    [
+        "%Y:%m:%d %H:%M:%S",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y:%m:%dT%H:%M:%S",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(date_time.trim(), format).ok())
+}
+
+/// Sub-seconds are the decimals of the seconds, i.e. `5` is half a second
+fn parse_sub_sec(sub_sec: &str) -> Option<u32> {
+    let digits: String = sub_sec.trim().chars().take(9).collect();
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(digits.parse::<u32>().ok()? * 10u32.pow(9 - digits.len() as u32))
+}
+
+/// Parse an offset such as `+02:00` into seconds east of UTC
+fn parse_utc_offset(offset: &str) -> Option<i32> {
+    let offset = offset.trim();
+    let sign = match offset.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let mut parts = offset[1..].splitn(2, ':');
+    let hours = parts.next()?.parse::<i32>().ok()?;
+    let minutes = parts.next().unwrap_or("0").parse::<i32>().ok()?;
+    let offset = sign * (hours * 3600 + minutes * 60);
+    if i64::from(offset).abs() <= MAX_UTC_OFFSET {
+        Some(offset)
     } else {
         None
+    }
+}
+
+/// GPS time is always UTC, so when the camera has a GPS fix the difference to the local time is
+/// the offset. It's rounded to 15 minutes since the clocks are never exactly in sync
+fn gps_utc_offset(reader: &Exif, local: &NaiveDateTime) -> Option<i32> {
+    let time = match reader.get_field(Tag::GPSTimeStamp, In::PRIMARY)?.value {
+        Value::Rational(ref values) if values.len() >= 3 => values
+            .iter()
+            .take(3)
+            .map(|v| v.to_f64())
+            .collect::<Vec<_>>(),
+        _ => return None,
     };
+    let seconds_of_day = time[0] * 3600.0 + time[1] * 60.0 + time[2];
+    if !seconds_of_day.is_finite() || seconds_of_day < 0.0 {
+        return None;
+    }
+    // Without a date the one giving the smallest offset is used
+    let date = field_as_ascii(reader, Tag::GPSDateStamp)
+        .and_then(|date| NaiveDate::parse_from_str(date.trim(), "%Y:%m:%d").ok())
+        .unwrap_or_else(|| local.date());
+    let gps = date.and_hms(0, 0, 0) + Duration::milliseconds((seconds_of_day * 1000.0) as i64);
+
+    let mut offset = (*local - gps).num_seconds();
+    if field_as_ascii(reader, Tag::GPSDateStamp).is_none() {
+        const DAY: i64 = 24 * 3600;
+        if offset > DAY / 2 {
+            offset -= DAY;
+        } else if offset < -DAY / 2 {
+            offset += DAY;
+        }
+    }
+    let offset = ((offset as f64 / 900.0).round() as i64) * 900;
+    if offset.abs() <= MAX_UTC_OFFSET {
+        Some(offset as i32)
+    } else {
+        None
+    }
+}
+
+pub fn extract_exif_metadata<P: AsRef<Path>>(path: P) -> Result<Metadata> {
+    let reader = read_exif(&path)?;
+
+    let capture_time = exif_capture_time(&reader);
 
     let rotation = find_orientation(&reader);
     let (width, height) = width_and_height(&reader)?;
@@ -290,12 +702,25 @@ pub fn extract_exif_metadata<P: AsRef<Path>>(path: P) -> Result<Metadata> {
     image_metadata.aperture = aperture(&reader);
     image_metadata.iso = field_as_uint(&reader, Tag::PhotographicSensitivity);
     image_metadata.flash = flash(&reader);
+    image_metadata.camera_make = field_as_ascii(&reader, Tag::Make);
+    image_metadata.camera_model = field_as_ascii(&reader, Tag::Model);
+    image_metadata.lens_model = field_as_ascii(&reader, Tag::LensModel);
+    image_metadata.focal_length = field_as_f32(&reader, Tag::FocalLength).filter(|f| *f > 0.0);
+    image_metadata.focal_length_35mm = focal_length_35mm(&reader, image_metadata.focal_length);
+    image_metadata.exposure_program =
+        field_as_uint(&reader, Tag::ExposureProgram).and_then(ExposureProgram::from_exif);
+    image_metadata.metering_mode =
+        field_as_uint(&reader, Tag::MeteringMode).and_then(MeteringMode::from_exif);
+    image_metadata.white_balance =
+        field_as_uint(&reader, Tag::WhiteBalance).and_then(WhiteBalance::from_exif);
     let type_specific = TypeSpecific::Image(image_metadata);
 
     Ok(Metadata {
         width,
         height,
-        date_time,
+        date_time: capture_time.as_ref().map(|t| t.utc),
+        local_date_time: capture_time.as_ref().and_then(|t| t.local),
+        utc_offset: capture_time.and_then(|t| t.offset),
         gps_location,
         rotation,
         type_specific,
@@ -316,6 +741,65 @@ fn extract_video_metadata_from_path<P: AsRef<Path>>(path: P) -> Result<Metadata>
     )?)
 }
 
+/// Tags that may hold the recording time, in order of preference. MP4, MOV and 3GP use
+/// `creation_time`, Matroska files often use `DATE_RECORDED` or `DATE` and AVI uses `date` when
+/// there is no `IDIT` chunk
+const VIDEO_DATE_TAGS: &[&str] = &[
+    "com.apple.quicktime.creationdate",
+    "creation_time",
+    "date_recorded",
+    "date",
+];
+
+/// Tags that may hold an ISO 6709 location, in order of preference
+const VIDEO_LOCATION_TAGS: &[&str] = &[
+    "com.apple.quicktime.location.ISO6709",
+    "location",
+    "location-eng",
+];
+
+/// Find the first of the given tags, ignoring case since Matroska tags are usually upper case
+fn find_video_tag<'a>(
+    tags: &[&'a serde_json::Map<String, SerdeValue>],
+    names: &[&str],
+) -> Option<&'a str> {
+    names.iter().find_map(|name| {
+        tags.iter().find_map(|tags| {
+            tags.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .and_then(|(_, value)| value.as_str())
+        })
+    })
+}
+
+fn parse_video_date_time(date_time: &str) -> Option<CaptureTime> {
+    let date_time = date_time.trim();
+    let with_offset = DateTime::parse_from_rfc3339(date_time)
+        .or_else(|_| DateTime::parse_from_str(date_time, "%Y-%m-%dT%H:%M:%S%z"));
+    if let Ok(with_offset) = with_offset {
+        // MP4 and MOV store UTC, marked with a Z, so that says nothing about the local time
+        if date_time.ends_with('Z') {
+            return Some(CaptureTime::utc(with_offset.with_timezone(&Utc)));
+        }
+        let offset = with_offset.offset().local_minus_utc();
+        return Some(CaptureTime::local(with_offset.naive_local(), Some(offset)));
+    }
+    // Without a time zone we have to assume UTC
+    [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y:%m:%d %H:%M:%S",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(date_time, format).ok())
+    .or_else(|| {
+        NaiveDate::parse_from_str(date_time, "%Y-%m-%d")
+            .ok()
+            .map(|date| date.and_hms(0, 0, 0))
+    })
+    .map(|naive| CaptureTime::utc(DateTime::<Utc>::from_utc(naive, Utc)))
+}
+
 fn extract_metadata_video(output: &Output) -> Result<Metadata> {
     let json_output: SerdeValue = serde_json::from_str(std::str::from_utf8(&output.stdout)?)?;
     let raw_metadata = json_output
@@ -328,44 +812,30 @@ fn extract_metadata_video(output: &Output) -> Result<Metadata> {
         .ok_or(anyhow!("Duration is not string"))?
         .parse::<f32>()?;
 
-    let tags = json_as_object(&format, "tags")?;
+    let streams = get_leaf_value(&raw_metadata, "streams")?
+        .as_array()
+        .ok_or(anyhow!("Not an array"))?;
 
-    let date_time = tags
-        .get("creation_time")
-        .and_then(|d| d.as_str())
-        .and_then(|r| DateTime::parse_from_rfc3339(r).ok());
+    // Containers disagree on where tags go, so look in the format first and then in the streams
+    let mut tags = vec![format.get("tags").and_then(|t| t.as_object())];
+    tags.extend(
+        streams
+            .iter()
+            .map(|stream| stream.get("tags").and_then(|t| t.as_object())),
+    );
+    let tags: Vec<_> = tags.into_iter().flatten().collect();
 
-    let date_time = if let Some(v) = date_time {
-        let timestamp = v.timestamp();
-        let naive = NaiveDateTime::from_timestamp(timestamp, 0);
-        Some(DateTime::<Utc>::from_utc(naive, Utc))
-    } else {
-        None
-    };
+    let capture_time = find_video_tag(&tags, VIDEO_DATE_TAGS).and_then(parse_video_date_time);
 
-    let framerate = tags
-        .get("com.android.capture.fps")
-        .and_then(|l| l.as_str())
-        .and_then(|r| r.parse::<f32>().ok());
+    let framerate =
+        find_video_tag(&tags, &["com.android.capture.fps"]).and_then(|r| r.parse::<f32>().ok());
 
-    let gps_string = if let Some(gps_string) = tags.get("location") {
-        Some(gps_string)
-    } else if let Some(gps_string) = tags.get("com.apple.quicktime.location.ISO6709") {
-        Some(gps_string)
-    } else {
-        None
-    };
-    let gps_location = gps_string
-        .and_then(|l| l.as_str())
-        .and_then(|r| gps_video(r).ok());
+    let gps_location = find_video_tag(&tags, VIDEO_LOCATION_TAGS).and_then(|r| gps_video(r).ok());
 
     let mut video_metadata = VideoMetadata::default();
     video_metadata.duration = duration;
     video_metadata.framerate = framerate;
 
-    let streams = get_leaf_value(&raw_metadata, "streams")?
-        .as_array()
-        .ok_or(anyhow!("Not an array"))?;
     for stream in streams {
         let stream = stream.as_object().ok_or(anyhow!("Not a JSON object"))?;
         if stream.get("codec_type") != Some(&json!("video")) {
@@ -373,8 +843,9 @@ fn extract_metadata_video(output: &Output) -> Result<Metadata> {
         }
         let width = json_as_u64(&stream, "width")?.try_into()?;
         let height = json_as_u64(&stream, "height")?.try_into()?;
-        let rotation = json_as_object(&stream, "tags")?
-            .get("rotate")
+        let rotation = json_as_object(&stream, "tags")
+            .ok()
+            .and_then(|tags| tags.get("rotate"))
             .and_then(|rot| rot.as_str())
             .and_then(|r| map_rotation(r).ok());
         let type_specific = TypeSpecific::Video(video_metadata);
@@ -382,7 +853,9 @@ fn extract_metadata_video(output: &Output) -> Result<Metadata> {
         return Ok(Metadata {
             width,
             height,
-            date_time,
+            date_time: capture_time.as_ref().map(|t| t.utc),
+            local_date_time: capture_time.as_ref().and_then(|t| t.local),
+            utc_offset: capture_time.and_then(|t| t.offset),
             gps_location,
             rotation,
             type_specific,
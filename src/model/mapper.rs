@@ -18,20 +18,28 @@ You should have received a copy of the GNU General Public License
 along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 use anyhow::anyhow;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use deunicode::deunicode;
+use fraction::prelude::Fraction;
 use futures::{Stream, StreamExt, TryStreamExt};
 use regex::Regex;
+use serde_json::Value as SerdeValue;
 use std::borrow::Borrow;
 use std::convert::{TryFrom, TryInto};
 use std::path::{Path, PathBuf};
-use tokio_postgres::types::ToSql;
+use tokio_postgres::types::{Json, ToSql};
 use tokio_postgres::{Client, Row};
 
 use super::types::EntityType;
 use crate::coord::Location;
+use crate::edit::EditOperation;
 use crate::error::Result;
 use crate::hash::Sha3;
+use crate::metadata::{
+    ExposureProgram, ImageMetadata, Metadata, MeteringMode, Rotate, TypeSpecific, VideoMetadata,
+    WhiteBalance,
+};
+use crate::storage::DerivativeFile;
 
 #[derive(Debug, PartialEq)]
 pub struct Entity {
@@ -44,7 +52,123 @@ pub struct Entity {
     pub size: u64,
     pub sha3: Sha3,
     pub created: Option<DateTime<Utc>>,
+    /// Wall-clock time where the media was captured
+    pub created_local: Option<NaiveDateTime>,
     pub location: Option<Location>,
+    pub original_name: String,
+    pub missing: bool,
+    /// Set by the user when the orientation of the file is wrong or missing
+    pub rotation: Option<Rotate>,
+    /// BlurHash of the thumbnail
+    pub blurhash: Option<String>,
+    /// When the original was last changed, as of when it was last read
+    pub modified: Option<DateTime<Utc>>,
+    /// Seconds east of UTC of `created_local`, `None` when the camera didn't say
+    pub utc_offset: Option<i32>,
+}
+
+/// Criteria used to select a set of entities. Fields that are empty match everything
+#[derive(Debug, Default)]
+pub struct EntityFilter {
+    pub ids: Vec<i32>,
+    pub media_type: Option<EntityType>,
+    /// Canonical name of a tag. Entities tagged with any of its children match as well
+    pub tag: Option<String>,
+    pub created_from: Option<DateTime<Utc>>,
+    pub created_to: Option<DateTime<Utc>>,
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    pub min_iso: Option<u32>,
+    pub max_iso: Option<u32>,
+    pub flash: Option<bool>,
+    /// Camera and lens names are matched ignoring case
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens_model: Option<String>,
+    /// Focal lengths are 35mm equivalents when known
+    pub min_focal_length: Option<f32>,
+    pub max_focal_length: Option<f32>,
+    /// Only entities whose raw metadata contains this, i.e. `{"exif": {"Make": "Canon"}}`
+    pub raw_metadata: Option<SerdeValue>,
+}
+
+impl EntityFilter {
+    /// Returns true if the filter looks at the stored metadata
+    pub fn has_metadata_criteria(&self) -> bool {
+        self.min_width.is_some()
+            || self.min_height.is_some()
+            || self.min_iso.is_some()
+            || self.max_iso.is_some()
+            || self.flash.is_some()
+            || self.camera_make.is_some()
+            || self.camera_model.is_some()
+            || self.lens_model.is_some()
+            || self.min_focal_length.is_some()
+            || self.max_focal_length.is_some()
+            || self.raw_metadata.is_some()
+    }
+}
+
+/// A generated thumbnail, preview or other resized copy of an entity
+#[derive(Debug, PartialEq)]
+pub struct Derivative {
+    pub eid: i32,
+    pub profile: String,
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub fit: String,
+    /// Extensions of the other formats the derivative is available in
+    pub alternatives: Vec<String>,
+}
+
+/// Non-destructive edits of an entity, applied in order to everything made from the original
+#[derive(Debug, PartialEq)]
+pub struct Edit {
+    pub eid: i32,
+    pub operations: Vec<EditOperation>,
+    pub updated: DateTime<Utc>,
+}
+
+/// Metadata read from the original at import, so it doesn't have to be read again for every
+/// request
+#[derive(Clone, Debug)]
+pub struct EntityMetadata {
+    pub eid: i32,
+    pub metadata: Metadata,
+    pub updated: DateTime<Utc>,
+}
+
+/// Every EXIF, XMP and IPTC field of an entity, as returned by `dump_metadata`
+#[derive(Debug, PartialEq)]
+pub struct RawMetadata {
+    pub eid: i32,
+    pub data: SerdeValue,
+    pub updated: DateTime<Utc>,
+}
+
+/// A change of the capture times of a set of entities, kept so it can be undone
+#[derive(Debug, PartialEq)]
+pub struct TimeCorrection {
+    pub id: i32,
+    pub description: String,
+    /// True if the new times were written to sidecars as well
+    pub sidecars: bool,
+    pub applied: DateTime<Utc>,
+    pub undone: Option<DateTime<Utc>>,
+}
+
+/// Capture time of an entity before and after a correction
+#[derive(Debug, PartialEq)]
+pub struct TimeCorrectionEntity {
+    pub cid: i32,
+    pub eid: i32,
+    pub old_created: Option<DateTime<Utc>>,
+    pub old_created_local: Option<NaiveDateTime>,
+    pub new_created: Option<DateTime<Utc>>,
+    pub new_created_local: Option<NaiveDateTime>,
+    pub old_utc_offset: Option<i32>,
+    pub new_utc_offset: Option<i32>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -62,7 +186,7 @@ pub struct TagToEntity {
 }
 
 impl Entity {
-    pub const COLS: [&'static str; 10] = [
+    pub const COLS: [&'static str; 17] = [
         "id",
         "media_type",
         "path",
@@ -73,6 +197,13 @@ impl Entity {
         "uploaded",
         "created",
         "location",
+        "original_name",
+        "missing",
+        "rotation",
+        "blurhash",
+        "created_local",
+        "modified",
+        "utc_offset",
     ];
 
     pub async fn insert<P1, P2, P3>(
@@ -84,7 +215,12 @@ impl Entity {
         size: u64,
         sha3: &Sha3,
         created: &Option<DateTime<Utc>>,
+        created_local: &Option<NaiveDateTime>,
+        utc_offset: &Option<i32>,
         location: &Option<Location>,
+        original_name: &str,
+        blurhash: &Option<String>,
+        modified: &Option<DateTime<Utc>>,
     ) -> Result<Self>
     where
         P1: AsRef<Path>,
@@ -104,7 +240,12 @@ impl Entity {
                                 size,
                                 sha3,
                                 created,
-                                location
+                                location,
+                                original_name,
+                                blurhash,
+                                created_local,
+                                modified,
+                                utc_offset
                             )
                             VALUES(
                                 $1,
@@ -114,7 +255,12 @@ impl Entity {
                                 $5,
                                 $6,
                                 $7,
-                                $8
+                                $8,
+                                $9,
+                                $10,
+                                $11,
+                                $12,
+                                $13
                             )
                             RETURNING {}
                         ",
@@ -139,6 +285,11 @@ impl Entity {
                         &sha3.as_ref(),
                         &created,
                         &location,
+                        &original_name,
+                        &blurhash,
+                        &created_local,
+                        &modified,
+                        &utc_offset,
                     ],
                 )
                 .await?,
@@ -159,8 +310,15 @@ impl Entity {
                             sha3 = $6,
                             uploaded = $7,
                             created = $8,
-                            location = $9
-                        WHERE id = $10
+                            location = $9,
+                            original_name = $10,
+                            missing = $11,
+                            rotation = $12,
+                            blurhash = $13,
+                            created_local = $14,
+                            modified = $15,
+                            utc_offset = $16
+                        WHERE id = $17
                         RETURNING {}
                     ",
                     Self::COLS.join(", "),
@@ -185,6 +343,13 @@ impl Entity {
                     &self.uploaded,
                     &self.created,
                     &self.location,
+                    &self.original_name,
+                    &self.missing,
+                    &self.rotation.as_ref().map(Rotate::degrees),
+                    &self.blurhash,
+                    &self.created_local,
+                    &self.modified,
+                    &self.utc_offset,
                     &self.id,
                 ],
             )
@@ -204,6 +369,15 @@ impl Entity {
             uploaded: row.try_get::<_, DateTime<Utc>>(7)?,
             created: row.try_get::<_, Option<DateTime<Utc>>>(8)?,
             location: row.try_get::<_, Option<Location>>(9)?,
+            original_name: row.try_get::<_, String>(10)?,
+            missing: row.try_get::<_, bool>(11)?,
+            rotation: row
+                .try_get::<_, Option<i16>>(12)?
+                .and_then(Rotate::from_degrees),
+            blurhash: row.try_get::<_, Option<String>>(13)?,
+            created_local: row.try_get::<_, Option<NaiveDateTime>>(14)?,
+            modified: row.try_get::<_, Option<DateTime<Utc>>>(15)?,
+            utc_offset: row.try_get::<_, Option<i32>>(16)?,
         })
     }
 
@@ -265,6 +439,128 @@ impl Entity {
             .map(|row| Ok(Self::from_row(&row?)?)))
     }
 
+    pub async fn list_filtered(client: &Client, filter: &EntityFilter) -> Result<Vec<Self>> {
+        let to_i32 = |value: Option<u32>| value.map(i32::try_from).transpose();
+        let min_width = to_i32(filter.min_width)?;
+        let min_height = to_i32(filter.min_height)?;
+        let min_iso = to_i32(filter.min_iso)?;
+        let max_iso = to_i32(filter.max_iso)?;
+
+        let mut conditions = Vec::new();
+        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if !filter.ids.is_empty() {
+            params.push(&filter.ids);
+            conditions.push(format!("id = ANY(${})", params.len()));
+        }
+        if let Some(ref media_type) = filter.media_type {
+            params.push(media_type);
+            conditions.push(format!("media_type = ${}", params.len()));
+        }
+        if let Some(ref tag) = filter.tag {
+            params.push(tag);
+            conditions.push(format!(
+                "
+                    id IN (
+                        WITH RECURSIVE deeptag AS (
+                            SELECT * FROM tag WHERE canonical_name = ${}
+                            UNION
+                            SELECT t.* FROM tag t JOIN deeptag dt ON dt.id = t.pid
+                        )
+                        SELECT t2e.eid FROM deeptag t JOIN tag_to_entity t2e ON t.id = t2e.tid
+                    )
+                ",
+                params.len()
+            ));
+        }
+        if let Some(ref created_from) = filter.created_from {
+            params.push(created_from);
+            conditions.push(format!("created >= ${}", params.len()));
+        }
+        if let Some(ref created_to) = filter.created_to {
+            params.push(created_to);
+            conditions.push(format!("created < ${}", params.len()));
+        }
+
+        let mut metadata_conditions = Vec::new();
+        if let Some(ref min_width) = min_width {
+            params.push(min_width);
+            metadata_conditions.push(format!("width >= ${}", params.len()));
+        }
+        if let Some(ref min_height) = min_height {
+            params.push(min_height);
+            metadata_conditions.push(format!("height >= ${}", params.len()));
+        }
+        if let Some(ref min_iso) = min_iso {
+            params.push(min_iso);
+            metadata_conditions.push(format!("iso >= ${}", params.len()));
+        }
+        if let Some(ref max_iso) = max_iso {
+            params.push(max_iso);
+            metadata_conditions.push(format!("iso <= ${}", params.len()));
+        }
+        if let Some(ref flash) = filter.flash {
+            params.push(flash);
+            metadata_conditions.push(format!("flash = ${}", params.len()));
+        }
+        if let Some(ref camera_make) = filter.camera_make {
+            params.push(camera_make);
+            metadata_conditions.push(format!("lower(camera_make) = lower(${})", params.len()));
+        }
+        if let Some(ref camera_model) = filter.camera_model {
+            params.push(camera_model);
+            metadata_conditions.push(format!("lower(camera_model) = lower(${})", params.len()));
+        }
+        if let Some(ref lens_model) = filter.lens_model {
+            params.push(lens_model);
+            metadata_conditions.push(format!("lower(lens_model) = lower(${})", params.len()));
+        }
+        if let Some(ref min_focal_length) = filter.min_focal_length {
+            params.push(min_focal_length);
+            metadata_conditions.push(format!(
+                "coalesce(focal_length_35mm, focal_length) >= ${}",
+                params.len()
+            ));
+        }
+        if let Some(ref max_focal_length) = filter.max_focal_length {
+            params.push(max_focal_length);
+            metadata_conditions.push(format!(
+                "coalesce(focal_length_35mm, focal_length) <= ${}",
+                params.len()
+            ));
+        }
+        if let Some(ref raw_metadata) = filter.raw_metadata {
+            params.push(raw_metadata);
+            conditions.push(format!(
+                "id IN (SELECT eid FROM raw_metadata WHERE data @> ${})",
+                params.len()
+            ));
+        }
+        if !metadata_conditions.is_empty() {
+            conditions.push(format!(
+                "id IN (SELECT eid FROM entity_metadata WHERE {})",
+                metadata_conditions.join(" AND ")
+            ));
+        }
+
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        let rows = client
+            .query(
+                format!(
+                    "SELECT {} FROM entity {} ORDER BY id",
+                    Self::COLS.join(", "),
+                    where_clause
+                )
+                .as_str(),
+                &params,
+            )
+            .await?;
+        rows.iter().map(Self::from_row).collect()
+    }
+
     pub async fn delete<T: Borrow<i32>>(client: &Client, id: T) -> Result<()> {
         let num_rows = &client
             .execute(
@@ -282,6 +578,587 @@ impl Entity {
     }
 }
 
+impl Derivative {
+    pub const COLS: [&'static str; 7] = [
+        "eid",
+        "profile",
+        "path",
+        "width",
+        "height",
+        "fit",
+        "alternatives",
+    ];
+
+    pub fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            eid: row.try_get::<_, i32>(0)?,
+            profile: row.try_get::<_, String>(1)?,
+            path: Path::new(row.try_get::<_, &str>(2)?).to_path_buf(),
+            width: row.try_get::<_, i32>(3)?.try_into()?,
+            height: row.try_get::<_, i32>(4)?.try_into()?,
+            fit: row.try_get::<_, String>(5)?,
+            alternatives: row.try_get::<_, Vec<String>>(6)?,
+        })
+    }
+
+    /// Paths of the derivative in the other formats it's available in
+    pub fn alternative_paths(&self) -> Vec<PathBuf> {
+        self.alternatives
+            .iter()
+            .map(|extension| self.path.with_extension(extension))
+            .collect()
+    }
+
+    /// Replace all derivatives of an entity with the given ones
+    pub async fn replace_for_eid<T: Borrow<i32>>(
+        client: &Client,
+        eid: T,
+        files: &[DerivativeFile],
+    ) -> Result<()> {
+        client
+            .execute("DELETE FROM derivative WHERE eid = $1", &[eid.borrow()])
+            .await?;
+        for file in files {
+            client
+                .execute(
+                    "
+                        INSERT INTO derivative(eid, profile, path, width, height, fit, alternatives)
+                        VALUES($1, $2, $3, $4, $5, $6, $7)
+                    ",
+                    &[
+                        eid.borrow(),
+                        &file.profile,
+                        &file
+                            .path
+                            .to_str()
+                            .ok_or(anyhow!("Derivative path contains non UTF-8 characters"))?,
+                        &i32::try_from(file.width)?,
+                        &i32::try_from(file.height)?,
+                        &file.fit.as_str(),
+                        &file
+                            .alternatives
+                            .iter()
+                            .map(|format| format.extension())
+                            .collect::<Vec<_>>(),
+                    ],
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn get<T: Borrow<i32>>(client: &Client, eid: T, profile: &str) -> Option<Self> {
+        let row = client
+            .query_opt(
+                format!(
+                    "SELECT {} FROM derivative WHERE eid = $1 AND profile = $2",
+                    Self::COLS.join(", ")
+                )
+                .as_str(),
+                &[eid.borrow(), &profile],
+            )
+            .await
+            .ok()
+            .flatten()?;
+        Self::from_row(&row).ok()
+    }
+
+    pub async fn list(client: &Client) -> Result<Vec<Self>> {
+        let rows = client
+            .query(
+                format!("SELECT {} FROM derivative", Self::COLS.join(", ")).as_str(),
+                &[],
+            )
+            .await?;
+        rows.iter().map(Self::from_row).collect()
+    }
+
+    pub async fn list_from_eids(client: &Client, eids: &[i32]) -> Result<Vec<Self>> {
+        let rows = client
+            .query(
+                format!(
+                    "SELECT {} FROM derivative WHERE eid IN (select(unnest($1::int[])))",
+                    Self::COLS.join(", ")
+                )
+                .as_str(),
+                &[&eids],
+            )
+            .await?;
+        rows.iter().map(Self::from_row).collect()
+    }
+}
+
+impl Edit {
+    pub const COLS: [&'static str; 3] = ["eid", "operations", "updated"];
+
+    pub fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            eid: row.try_get::<_, i32>(0)?,
+            operations: row.try_get::<_, Json<Vec<EditOperation>>>(1)?.0,
+            updated: row.try_get::<_, DateTime<Utc>>(2)?,
+        })
+    }
+
+    pub async fn get<T: Borrow<i32>>(client: &Client, eid: T) -> Option<Self> {
+        let row = client
+            .query_opt(
+                format!("SELECT {} FROM edit WHERE eid = $1", Self::COLS.join(", ")).as_str(),
+                &[eid.borrow()],
+            )
+            .await
+            .ok()
+            .flatten()?;
+        Self::from_row(&row).ok()
+    }
+
+    /// Returns the edits of an entity, which is empty for unedited ones
+    pub async fn operations_for<T: Borrow<i32>>(
+        client: &Client,
+        eid: T,
+    ) -> Result<Vec<EditOperation>> {
+        let row = client
+            .query_opt(
+                "SELECT operations FROM edit WHERE eid = $1",
+                &[eid.borrow()],
+            )
+            .await?;
+        Ok(match row {
+            Some(row) => row.try_get::<_, Json<Vec<EditOperation>>>(0)?.0,
+            None => Vec::new(),
+        })
+    }
+
+    /// Replace the edits of an entity
+    pub async fn set<T: Borrow<i32>>(
+        client: &Client,
+        eid: T,
+        operations: &[EditOperation],
+    ) -> Result<Self> {
+        let row = client
+            .query_one(
+                format!(
+                    "
+                        INSERT INTO edit(eid, operations)
+                        VALUES($1, $2)
+                        ON CONFLICT (eid) DO UPDATE
+                        SET operations = EXCLUDED.operations, updated = current_timestamp
+                        RETURNING {}
+                    ",
+                    Self::COLS.join(", ")
+                )
+                .as_str(),
+                &[eid.borrow(), &Json(operations)],
+            )
+            .await?;
+        Self::from_row(&row)
+    }
+
+    /// Go back to the original
+    pub async fn delete<T: Borrow<i32>>(client: &Client, eid: T) -> Result<()> {
+        client
+            .execute("DELETE FROM edit WHERE eid = $1", &[eid.borrow()])
+            .await?;
+        Ok(())
+    }
+}
+
+impl EntityMetadata {
+    pub const COLS: [&'static str; 24] = [
+        "eid",
+        "media_type",
+        "width",
+        "height",
+        "date_time",
+        "location",
+        "rotation",
+        "exposure_time",
+        "aperture",
+        "iso",
+        "flash",
+        "duration",
+        "frame_rate",
+        "updated",
+        "camera_make",
+        "camera_model",
+        "lens_model",
+        "focal_length",
+        "focal_length_35mm",
+        "exposure_program",
+        "metering_mode",
+        "white_balance",
+        "date_time_local",
+        "utc_offset",
+    ];
+
+    pub fn from_row(row: &Row) -> Result<Self> {
+        let type_specific = match row.try_get::<_, EntityType>(1)? {
+            EntityType::Image => TypeSpecific::Image(ImageMetadata {
+                exposure_time: row.try_get::<_, Option<&str>>(7)?.and_then(parse_fraction),
+                aperture: row.try_get::<_, Option<f32>>(8)?,
+                iso: row
+                    .try_get::<_, Option<i32>>(9)?
+                    .map(u32::try_from)
+                    .transpose()?,
+                flash: row.try_get::<_, Option<bool>>(10)?,
+                camera_make: row.try_get::<_, Option<String>>(14)?,
+                camera_model: row.try_get::<_, Option<String>>(15)?,
+                lens_model: row.try_get::<_, Option<String>>(16)?,
+                focal_length: row.try_get::<_, Option<f32>>(17)?,
+                focal_length_35mm: row.try_get::<_, Option<f32>>(18)?,
+                exposure_program: row
+                    .try_get::<_, Option<i16>>(19)?
+                    .and_then(|v| ExposureProgram::from_exif(v as u32)),
+                metering_mode: row
+                    .try_get::<_, Option<i16>>(20)?
+                    .and_then(|v| MeteringMode::from_exif(v as u32)),
+                white_balance: row
+                    .try_get::<_, Option<i16>>(21)?
+                    .and_then(|v| WhiteBalance::from_exif(v as u32)),
+            }),
+            EntityType::Video => TypeSpecific::Video(VideoMetadata {
+                duration: row.try_get::<_, Option<f32>>(11)?.unwrap_or_default(),
+                framerate: row.try_get::<_, Option<f32>>(12)?,
+            }),
+        };
+        Ok(Self {
+            eid: row.try_get::<_, i32>(0)?,
+            metadata: Metadata {
+                width: row.try_get::<_, i32>(2)?.try_into()?,
+                height: row.try_get::<_, i32>(3)?.try_into()?,
+                date_time: row.try_get::<_, Option<DateTime<Utc>>>(4)?,
+                local_date_time: row.try_get::<_, Option<NaiveDateTime>>(22)?,
+                utc_offset: row.try_get::<_, Option<i32>>(23)?,
+                gps_location: row.try_get::<_, Option<Location>>(5)?,
+                rotation: row
+                    .try_get::<_, Option<i16>>(6)?
+                    .and_then(Rotate::from_degrees),
+                type_specific,
+            },
+            updated: row.try_get::<_, DateTime<Utc>>(13)?,
+        })
+    }
+
+    /// Store the metadata of an entity, replacing what was stored before
+    pub async fn set<T: Borrow<i32>>(client: &Client, eid: T, metadata: &Metadata) -> Result<Self> {
+        let (media_type, image, video) = match metadata.type_specific {
+            TypeSpecific::Image(ref image) => (EntityType::Image, Some(image), None),
+            TypeSpecific::Video(ref video) => (EntityType::Video, None, Some(video)),
+        };
+        let row = client
+            .query_one(
+                format!(
+                    "
+                        INSERT INTO entity_metadata(
+                            eid, media_type, width, height, date_time, location, rotation,
+                            exposure_time, aperture, iso, flash, duration, frame_rate,
+                            camera_make, camera_model, lens_model, focal_length,
+                            focal_length_35mm, exposure_program, metering_mode, white_balance,
+                            date_time_local, utc_offset
+                        )
+                        VALUES(
+                            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15,
+                            $16, $17, $18, $19, $20, $21, $22, $23
+                        )
+                        ON CONFLICT (eid) DO UPDATE
+                        SET media_type = EXCLUDED.media_type,
+                            width = EXCLUDED.width,
+                            height = EXCLUDED.height,
+                            date_time = EXCLUDED.date_time,
+                            location = EXCLUDED.location,
+                            rotation = EXCLUDED.rotation,
+                            exposure_time = EXCLUDED.exposure_time,
+                            aperture = EXCLUDED.aperture,
+                            iso = EXCLUDED.iso,
+                            flash = EXCLUDED.flash,
+                            duration = EXCLUDED.duration,
+                            frame_rate = EXCLUDED.frame_rate,
+                            camera_make = EXCLUDED.camera_make,
+                            camera_model = EXCLUDED.camera_model,
+                            lens_model = EXCLUDED.lens_model,
+                            focal_length = EXCLUDED.focal_length,
+                            focal_length_35mm = EXCLUDED.focal_length_35mm,
+                            exposure_program = EXCLUDED.exposure_program,
+                            metering_mode = EXCLUDED.metering_mode,
+                            white_balance = EXCLUDED.white_balance,
+                            date_time_local = EXCLUDED.date_time_local,
+                            utc_offset = EXCLUDED.utc_offset,
+                            updated = current_timestamp
+                        RETURNING {}
+                    ",
+                    Self::COLS.join(", ")
+                )
+                .as_str(),
+                &[
+                    eid.borrow(),
+                    &media_type,
+                    &i32::try_from(metadata.width)?,
+                    &i32::try_from(metadata.height)?,
+                    &metadata.date_time,
+                    &metadata.gps_location,
+                    &metadata.rotation.as_ref().map(Rotate::degrees),
+                    &image
+                        .and_then(|i| i.exposure_time.as_ref())
+                        .map(|f| f.to_string()),
+                    &image.and_then(|i| i.aperture),
+                    &image.and_then(|i| i.iso).map(i32::try_from).transpose()?,
+                    &image.and_then(|i| i.flash),
+                    &video.map(|v| v.duration),
+                    &video.and_then(|v| v.framerate),
+                    &image.and_then(|i| i.camera_make.as_ref()),
+                    &image.and_then(|i| i.camera_model.as_ref()),
+                    &image.and_then(|i| i.lens_model.as_ref()),
+                    &image.and_then(|i| i.focal_length),
+                    &image.and_then(|i| i.focal_length_35mm),
+                    &image
+                        .and_then(|i| i.exposure_program)
+                        .map(|v| v.to_exif() as i16),
+                    &image
+                        .and_then(|i| i.metering_mode)
+                        .map(|v| v.to_exif() as i16),
+                    &image
+                        .and_then(|i| i.white_balance)
+                        .map(|v| v.to_exif() as i16),
+                    &metadata.local_date_time,
+                    &metadata.utc_offset,
+                ],
+            )
+            .await?;
+        Self::from_row(&row)
+    }
+
+    pub async fn get<T: Borrow<i32>>(client: &Client, eid: T) -> Option<Self> {
+        let row = client
+            .query_opt(
+                format!(
+                    "SELECT {} FROM entity_metadata WHERE eid = $1",
+                    Self::COLS.join(", ")
+                )
+                .as_str(),
+                &[eid.borrow()],
+            )
+            .await
+            .ok()
+            .flatten()?;
+        Self::from_row(&row).ok()
+    }
+
+    pub async fn list_from_eids(client: &Client, eids: &[i32]) -> Result<Vec<Self>> {
+        let rows = client
+            .query(
+                format!(
+                    "SELECT {} FROM entity_metadata WHERE eid IN (select(unnest($1::int[])))",
+                    Self::COLS.join(", ")
+                )
+                .as_str(),
+                &[&eids],
+            )
+            .await?;
+        rows.iter().map(Self::from_row).collect()
+    }
+}
+
+impl RawMetadata {
+    pub const COLS: [&'static str; 3] = ["eid", "data", "updated"];
+
+    pub fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            eid: row.try_get::<_, i32>(0)?,
+            data: row.try_get::<_, SerdeValue>(1)?,
+            updated: row.try_get::<_, DateTime<Utc>>(2)?,
+        })
+    }
+
+    pub async fn get<T: Borrow<i32>>(client: &Client, eid: T) -> Option<Self> {
+        let row = client
+            .query_opt(
+                format!(
+                    "SELECT {} FROM raw_metadata WHERE eid = $1",
+                    Self::COLS.join(", ")
+                )
+                .as_str(),
+                &[eid.borrow()],
+            )
+            .await
+            .ok()
+            .flatten()?;
+        Self::from_row(&row).ok()
+    }
+
+    /// Replace the raw metadata of an entity
+    pub async fn set<T: Borrow<i32>>(client: &Client, eid: T, data: &SerdeValue) -> Result<()> {
+        client
+            .execute(
+                "
+                    INSERT INTO raw_metadata(eid, data)
+                    VALUES($1, $2)
+                    ON CONFLICT (eid) DO UPDATE
+                    SET data = EXCLUDED.data, updated = current_timestamp
+                ",
+                &[eid.borrow(), data],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+impl TimeCorrection {
+    pub const COLS: [&'static str; 5] = ["id", "description", "sidecars", "applied", "undone"];
+
+    pub fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            id: row.try_get::<_, i32>(0)?,
+            description: row.try_get::<_, String>(1)?,
+            sidecars: row.try_get::<_, bool>(2)?,
+            applied: row.try_get::<_, DateTime<Utc>>(3)?,
+            undone: row.try_get::<_, Option<DateTime<Utc>>>(4)?,
+        })
+    }
+
+    pub async fn insert(client: &Client, description: &str, sidecars: bool) -> Result<Self> {
+        let row = client
+            .query_one(
+                format!(
+                    "
+                        INSERT INTO time_correction(description, sidecars)
+                        VALUES($1, $2)
+                        RETURNING {}
+                    ",
+                    Self::COLS.join(", ")
+                )
+                .as_str(),
+                &[&description, &sidecars],
+            )
+            .await?;
+        Self::from_row(&row)
+    }
+
+    pub async fn get<T: Borrow<i32>>(client: &Client, id: T) -> Option<Self> {
+        let row = client
+            .query_opt(
+                format!(
+                    "SELECT {} FROM time_correction WHERE id = $1",
+                    Self::COLS.join(", ")
+                )
+                .as_str(),
+                &[id.borrow()],
+            )
+            .await
+            .ok()
+            .flatten()?;
+        Self::from_row(&row).ok()
+    }
+
+    /// Returns true if the capture time of the entity has been corrected and not undone
+    pub async fn is_corrected<T: Borrow<i32>>(client: &Client, eid: T) -> Result<bool> {
+        let row = client
+            .query_one(
+                "
+                    SELECT EXISTS(
+                        SELECT 1 FROM time_correction_entity
+                        JOIN time_correction ON time_correction.id = time_correction_entity.cid
+                        WHERE time_correction_entity.eid = $1
+                            AND time_correction.undone IS NULL
+                    )
+                ",
+                &[eid.borrow()],
+            )
+            .await?;
+        Ok(row.try_get::<_, bool>(0)?)
+    }
+
+    pub async fn set_undone(&mut self, client: &Client) -> Result<()> {
+        let row = client
+            .query_one(
+                "
+                    UPDATE time_correction SET undone = current_timestamp
+                    WHERE id = $1
+                    RETURNING undone
+                ",
+                &[&self.id],
+            )
+            .await?;
+        self.undone = row.try_get::<_, Option<DateTime<Utc>>>(0)?;
+        Ok(())
+    }
+}
+
+impl TimeCorrectionEntity {
+    pub const COLS: [&'static str; 8] = [
+        "cid",
+        "eid",
+        "old_created",
+        "old_created_local",
+        "new_created",
+        "new_created_local",
+        "old_utc_offset",
+        "new_utc_offset",
+    ];
+
+    pub fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            cid: row.try_get::<_, i32>(0)?,
+            eid: row.try_get::<_, i32>(1)?,
+            old_created: row.try_get::<_, Option<DateTime<Utc>>>(2)?,
+            old_created_local: row.try_get::<_, Option<NaiveDateTime>>(3)?,
+            new_created: row.try_get::<_, Option<DateTime<Utc>>>(4)?,
+            new_created_local: row.try_get::<_, Option<NaiveDateTime>>(5)?,
+            old_utc_offset: row.try_get::<_, Option<i32>>(6)?,
+            new_utc_offset: row.try_get::<_, Option<i32>>(7)?,
+        })
+    }
+
+    pub async fn insert(&self, client: &Client) -> Result<()> {
+        client
+            .execute(
+                format!(
+                    "
+                        INSERT INTO time_correction_entity({})
+                        VALUES($1, $2, $3, $4, $5, $6, $7, $8)
+                    ",
+                    Self::COLS.join(", ")
+                )
+                .as_str(),
+                &[
+                    &self.cid,
+                    &self.eid,
+                    &self.old_created,
+                    &self.old_created_local,
+                    &self.new_created,
+                    &self.new_created_local,
+                    &self.old_utc_offset,
+                    &self.new_utc_offset,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_for_correction<T: Borrow<i32>>(client: &Client, cid: T) -> Result<Vec<Self>> {
+        let rows = client
+            .query(
+                format!(
+                    "SELECT {} FROM time_correction_entity WHERE cid = $1 ORDER BY eid",
+                    Self::COLS.join(", ")
+                )
+                .as_str(),
+                &[cid.borrow()],
+            )
+            .await?;
+        rows.iter().map(Self::from_row).collect()
+    }
+}
+
+/// Exposure times are stored as text, i.e. 1/250
+fn parse_fraction(value: &str) -> Option<Fraction> {
+    let mut parts = value.splitn(2, '/');
+    let numer = parts.next()?.trim().parse::<u64>().ok()?;
+    let denom = match parts.next() {
+        Some(denom) => denom.trim().parse::<u64>().ok()?,
+        None => 1,
+    };
+    Some(Fraction::new(numer, denom))
+}
+
 impl Tag {
     pub const COLS: [&'static str; 4] = ["id", "pid", "canonical_name", "name"];
 
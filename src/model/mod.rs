@@ -21,6 +21,9 @@ mod mapper;
 mod schema;
 mod types;
 
-pub use self::mapper::{Entity, Tag, TagToEntity};
+pub use self::mapper::{
+    Derivative, Edit, Entity, EntityFilter, EntityMetadata, RawMetadata, Tag, TagToEntity,
+    TimeCorrection, TimeCorrectionEntity,
+};
 pub use self::schema::create_schema;
 pub use self::types::EntityType;
@@ -52,12 +52,235 @@ pub async fn create_schema(client: &Client) -> Result<()> {
                     uploaded timestamp with time zone NOT NULL DEFAULT current_timestamp,
                     created timestamp with time zone,
                     location geography(point),
+                    original_name varchar NOT NULL DEFAULT '',
+                    missing boolean NOT NULL DEFAULT false,
+                    rotation smallint,
+                    blurhash varchar,
+                    created_local timestamp,
+                    modified timestamp with time zone,
+                    utc_offset integer,
                     unique (sha3)
                 )
             ",
             &[],
         )
         .await?;
+    client
+        .execute(
+            "ALTER TABLE entity ADD COLUMN IF NOT EXISTS original_name varchar NOT NULL DEFAULT ''",
+            &[],
+        )
+        .await?;
+    // Media imported before the name was kept gets the name of the file as a best guess
+    client
+        .execute(
+            "UPDATE entity SET original_name = regexp_replace(path, '^.*/', '') WHERE original_name = ''",
+            &[],
+        )
+        .await?;
+    client
+        .execute(
+            "ALTER TABLE entity ADD COLUMN IF NOT EXISTS missing boolean NOT NULL DEFAULT false",
+            &[],
+        )
+        .await?;
+    client
+        .execute(
+            "ALTER TABLE entity ADD COLUMN IF NOT EXISTS rotation smallint",
+            &[],
+        )
+        .await?;
+    client
+        .execute(
+            "ALTER TABLE entity ADD COLUMN IF NOT EXISTS blurhash varchar",
+            &[],
+        )
+        .await?;
+    client
+        .execute(
+            "ALTER TABLE entity ADD COLUMN IF NOT EXISTS created_local timestamp",
+            &[],
+        )
+        .await?;
+    client
+        .execute(
+            "ALTER TABLE entity ADD COLUMN IF NOT EXISTS modified timestamp with time zone",
+            &[],
+        )
+        .await?;
+    client
+        .execute(
+            "ALTER TABLE entity ADD COLUMN IF NOT EXISTS utc_offset integer",
+            &[],
+        )
+        .await?;
+    client
+        .execute(
+            "
+                CREATE TABLE IF NOT EXISTS derivative(
+                    eid integer NOT NULL references entity(id) ON DELETE CASCADE,
+                    profile varchar NOT NULL,
+                    path varchar NOT NULL,
+                    width integer NOT NULL,
+                    height integer NOT NULL,
+                    fit varchar NOT NULL,
+                    alternatives varchar[] NOT NULL DEFAULT '{}',
+                    unique (eid, profile)
+                )
+            ",
+            &[],
+        )
+        .await?;
+    client
+        .execute(
+            "ALTER TABLE derivative ADD COLUMN IF NOT EXISTS alternatives varchar[] NOT NULL DEFAULT '{}'",
+            &[],
+        )
+        .await?;
+    client
+        .execute(
+            "
+                CREATE TABLE IF NOT EXISTS edit(
+                    eid integer PRIMARY KEY NOT NULL references entity(id) ON DELETE CASCADE,
+                    operations jsonb NOT NULL,
+                    updated timestamp with time zone NOT NULL DEFAULT current_timestamp
+                )
+            ",
+            &[],
+        )
+        .await?;
+    client
+        .execute(
+            "
+                CREATE TABLE IF NOT EXISTS entity_metadata(
+                    eid integer PRIMARY KEY NOT NULL references entity(id) ON DELETE CASCADE,
+                    media_type entity_type NOT NULL,
+                    width integer NOT NULL,
+                    height integer NOT NULL,
+                    date_time timestamp with time zone,
+                    location geography(point),
+                    rotation smallint,
+                    exposure_time varchar,
+                    aperture real,
+                    iso integer,
+                    flash boolean,
+                    duration real,
+                    frame_rate real,
+                    updated timestamp with time zone NOT NULL DEFAULT current_timestamp,
+                    camera_make varchar,
+                    camera_model varchar,
+                    lens_model varchar,
+                    focal_length real,
+                    focal_length_35mm real,
+                    exposure_program smallint,
+                    metering_mode smallint,
+                    white_balance smallint,
+                    date_time_local timestamp,
+                    utc_offset integer
+                )
+            ",
+            &[],
+        )
+        .await?;
+    for column in &[
+        "camera_make varchar",
+        "camera_model varchar",
+        "lens_model varchar",
+        "focal_length real",
+        "focal_length_35mm real",
+        "exposure_program smallint",
+        "metering_mode smallint",
+        "white_balance smallint",
+        "date_time_local timestamp",
+        "utc_offset integer",
+    ] {
+        client
+            .execute(
+                format!(
+                    "ALTER TABLE entity_metadata ADD COLUMN IF NOT EXISTS {}",
+                    column
+                )
+                .as_str(),
+                &[],
+            )
+            .await?;
+    }
+    // Media imported before the offset was kept has it when the file had one. The local time may
+    // have been corrected since, so it's worked out from the stored times
+    client
+        .execute(
+            "
+                UPDATE entity SET utc_offset = extract(epoch FROM created_local - (created AT TIME ZONE 'UTC'))
+                WHERE utc_offset IS NULL
+                    AND created IS NOT NULL
+                    AND created_local IS NOT NULL
+                    AND id IN (SELECT eid FROM entity_metadata WHERE utc_offset IS NOT NULL)
+            ",
+            &[],
+        )
+        .await?;
+    client
+        .execute(
+            "
+                CREATE TABLE IF NOT EXISTS raw_metadata(
+                    eid integer PRIMARY KEY NOT NULL references entity(id) ON DELETE CASCADE,
+                    data jsonb NOT NULL,
+                    updated timestamp with time zone NOT NULL DEFAULT current_timestamp
+                )
+            ",
+            &[],
+        )
+        .await?;
+    client
+        .execute(
+            "CREATE INDEX IF NOT EXISTS raw_metadata_data ON raw_metadata USING gin (data)",
+            &[],
+        )
+        .await?;
+    client
+        .execute(
+            "
+                CREATE TABLE IF NOT EXISTS time_correction(
+                    id serial PRIMARY KEY NOT NULL,
+                    description varchar NOT NULL,
+                    sidecars boolean NOT NULL DEFAULT false,
+                    applied timestamp with time zone NOT NULL DEFAULT current_timestamp,
+                    undone timestamp with time zone
+                )
+            ",
+            &[],
+        )
+        .await?;
+    client
+        .execute(
+            "
+                CREATE TABLE IF NOT EXISTS time_correction_entity(
+                    cid integer NOT NULL references time_correction(id) ON DELETE CASCADE,
+                    eid integer NOT NULL references entity(id) ON DELETE CASCADE,
+                    old_created timestamp with time zone,
+                    old_created_local timestamp,
+                    new_created timestamp with time zone,
+                    new_created_local timestamp,
+                    old_utc_offset integer,
+                    new_utc_offset integer,
+                    unique (cid, eid)
+                )
+            ",
+            &[],
+        )
+        .await?;
+    for column in &["old_utc_offset integer", "new_utc_offset integer"] {
+        client
+            .execute(
+                format!(
+                    "ALTER TABLE time_correction_entity ADD COLUMN IF NOT EXISTS {}",
+                    column
+                )
+                .as_str(),
+                &[],
+            )
+            .await?;
+    }
     client
         .execute(
             "
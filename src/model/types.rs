@@ -17,9 +17,11 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
+use anyhow::anyhow;
 use bytes::{BufMut, BytesMut};
 use postgres_types::{to_sql_checked, FromSql, IsNull, Kind, ToSql, Type};
 use std::error::Error as ErrorTrait;
+use std::str::FromStr;
 
 use crate::coord::Location;
 
@@ -33,6 +35,18 @@ pub enum EntityType {
     Video,
 }
 
+impl FromStr for EntityType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "image" => Ok(EntityType::Image),
+            "video" => Ok(EntityType::Video),
+            _ => Err(anyhow!("Unknown media type {}, expected image or video", s)),
+        }
+    }
+}
+
 impl ToSql for Location {
     fn to_sql(
         &self,
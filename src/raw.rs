@@ -0,0 +1,197 @@
+/*
+Image database, allows the user to host a database themselves,
+with the possibilities to tag and search after images.
+Copyright (C) 2020 Cybercom group AB, Sweden
+By Christoffer Dahl, Johanna Hultberg, Andreas Runfalk and Margareta Vi
+
+Image database is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, GenericImageView, ImageFormat};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+use crate::color::{convert_to_srgb, exif_color_profile, jpeg_color_profile};
+use crate::metadata::read_exif;
+
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_STRIP_OFFSETS: u16 = 0x0111;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x0117;
+const TAG_SUB_IFDS: u16 = 0x014a;
+const TAG_JPEG_OFFSET: u16 = 0x0201;
+const TAG_JPEG_LENGTH: u16 = 0x0202;
+
+/// Compression values used for JPEG data in TIFF
+const COMPRESSION_OLD_JPEG: u32 = 6;
+const COMPRESSION_JPEG: u32 = 7;
+
+/// Embedded previews smaller than this on their longest side are only meant for the camera's
+/// own display, so the raw is developed instead
+const MIN_PREVIEW_SIZE: u32 = 1024;
+
+/// Stop after this many IFDs in case a broken file has a loop we didn't catch
+const MAX_IFDS: usize = 64;
+
+/// Minimal TIFF reader, just enough to find JPEG data in the IFDs of TIFF based raw formats
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        // The magic number is not checked as ORF and RW2 use their own
+        let big_endian = match data.get(0..2)? {
+            b"II" => false,
+            b"MM" => true,
+            _ => return None,
+        };
+        Some(Self { data, big_endian })
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// Returns the values of a SHORT or LONG entry at the given offset
+    fn values(&self, entry: usize) -> Option<Vec<u32>> {
+        let field_type = self.u16_at(entry + 2)?;
+        let count = self.u32_at(entry + 4)? as usize;
+        let size = match field_type {
+            3 => 2,
+            4 | 13 => 4,
+            _ => return None,
+        };
+        let start = if size * count <= 4 {
+            entry + 8
+        } else {
+            self.u32_at(entry + 8)? as usize
+        };
+        (0..count)
+            .map(|i| match size {
+                2 => self.u16_at(start + i * 2).map(u32::from),
+                _ => self.u32_at(start + i * 4),
+            })
+            .collect()
+    }
+
+    /// Walk all IFDs, including sub IFDs, and return every chunk of data that looks like a JPEG
+    fn jpegs(&self) -> Vec<&'a [u8]> {
+        let mut jpegs = Vec::new();
+        let mut queue: Vec<usize> = self.u32_at(4).map(|o| o as usize).into_iter().collect();
+        let mut visited = HashSet::new();
+
+        while let Some(ifd) = queue.pop() {
+            if ifd == 0 || !visited.insert(ifd) || visited.len() > MAX_IFDS {
+                continue;
+            }
+            let count = match self.u16_at(ifd) {
+                Some(count) => count as usize,
+                None => continue,
+            };
+
+            let mut compression = None;
+            let mut strip = (None, None);
+            let mut jpeg = (None, None);
+            for i in 0..count {
+                let entry = ifd + 2 + i * 12;
+                let tag = match self.u16_at(entry) {
+                    Some(tag) => tag,
+                    None => break,
+                };
+                let values = self.values(entry).unwrap_or_default();
+                let first = values.first().copied();
+                match tag {
+                    TAG_COMPRESSION => compression = first,
+                    // Only single strip images are of interest, previews are never split up
+                    TAG_STRIP_OFFSETS if values.len() == 1 => strip.0 = first,
+                    TAG_STRIP_BYTE_COUNTS if values.len() == 1 => strip.1 = first,
+                    TAG_JPEG_OFFSET => jpeg.0 = first,
+                    TAG_JPEG_LENGTH => jpeg.1 = first,
+                    TAG_SUB_IFDS => queue.extend(values.iter().map(|&o| o as usize)),
+                    _ => {}
+                }
+            }
+
+            if compression == Some(COMPRESSION_OLD_JPEG) || compression == Some(COMPRESSION_JPEG) {
+                jpegs.extend(self.chunk(strip));
+            }
+            jpegs.extend(self.chunk(jpeg));
+
+            if let Some(next) = self.u32_at(ifd + 2 + count * 12) {
+                queue.push(next as usize);
+            }
+        }
+        jpegs
+    }
+
+    fn chunk(&self, (offset, len): (Option<u32>, Option<u32>)) -> Option<&'a [u8]> {
+        let offset = offset? as usize;
+        let chunk = self.data.get(offset..offset + len? as usize)?;
+        if chunk.starts_with(&[0xff, 0xd8]) {
+            Some(chunk)
+        } else {
+            None
+        }
+    }
+}
+
+/// Decode the largest JPEG preview embedded in a TIFF based raw file, converted to sRGB. Note that
+/// the preview is stored as the sensor sees it, so the orientation of the raw must still be applied
+pub fn open_embedded_preview<P: AsRef<Path>>(path: P) -> Result<DynamicImage> {
+    let data = fs::read(&path)?;
+    let tiff = Tiff::new(&data).ok_or(anyhow!("Not a TIFF based raw file"))?;
+
+    let mut jpegs = tiff.jpegs();
+    jpegs.sort_by_key(|jpeg| std::cmp::Reverse(jpeg.len()));
+
+    // Lossless JPEG, as used for the raw data in DNG, can't be decoded so try each in turn
+    let (jpeg, img) = jpegs
+        .into_iter()
+        .filter_map(|jpeg| {
+            image::load_from_memory_with_format(jpeg, ImageFormat::JPEG)
+                .ok()
+                .map(|img| (jpeg, img))
+        })
+        .find(|(_, img)| img.width().max(img.height()) >= MIN_PREVIEW_SIZE)
+        .ok_or(anyhow!("No usable embedded preview"))?;
+
+    // The preview is in the colour space the camera was set to, which is only in the EXIF of the
+    // raw file itself unless the preview has its own
+    let profile = jpeg_color_profile(jpeg).or_else(|| {
+        read_exif(&path)
+            .ok()
+            .and_then(|exif| exif_color_profile(&exif))
+    });
+    Ok(match profile {
+        Some(profile) => convert_to_srgb(img, &profile),
+        None => img,
+    })
+}
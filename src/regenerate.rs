@@ -0,0 +1,242 @@
+/*
+Image database, allows the user to host a database themselves,
+with the possibilities to tag and search after images.
+Copyright (C) 2020 Cybercom group AB, Sweden
+By Christoffer Dahl, Johanna Hultberg, Andreas Runfalk and Margareta Vi
+
+Image database is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::anyhow;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::fmt;
+use tokio_postgres::Client;
+
+use crate::config::Config;
+use crate::dump::dump_metadata;
+use crate::edit::EditOperation;
+use crate::error::Result;
+use crate::metadata::{Metadata, Rotate};
+use crate::model::{
+    Derivative, Edit, Entity, EntityFilter, EntityMetadata, RawMetadata, TimeCorrection,
+};
+use crate::thumbnail::{create_thumbnail_in_place, placeholder_hash};
+use crate::util::run_blocking;
+
+#[derive(Debug, Default)]
+pub struct RegenerateSummary {
+    pub regenerated: Vec<i32>,
+    pub failed: Vec<(i32, String)>,
+}
+
+impl fmt::Display for RegenerateSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Regenerated: {}", self.regenerated.len())?;
+        write!(f, "Failed: {}", self.failed.len())?;
+        for (id, reason) in self.failed.iter() {
+            write!(f, "\n  {}: {}", id, reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Rebuild thumbnail and preview from the original, with the current rotation and edits applied
+pub async fn regenerate_entity(client: &Client, config: &Config, mut entity: Entity) -> Result<()> {
+    let path = entity.path.clone();
+    let sha3 = entity.sha3.clone();
+    let options = config.derivatives.clone();
+    let rotation = entity.rotation.clone();
+    let edits = Edit::operations_for(client, entity.id).await?;
+    let storage = run_blocking(move || {
+        Ok(create_thumbnail_in_place(
+            &path,
+            &sha3,
+            &options,
+            rotation.as_ref(),
+            &edits,
+        )?)
+    })
+    .await?;
+    entity.thumbnail_path = storage.thumbnail;
+    entity.preview_path = storage.preview;
+    entity.blurhash = storage.blurhash;
+    entity.save(client).await?;
+    Derivative::replace_for_eid(client, entity.id, &storage.derivatives).await
+}
+
+/// Override the rotation of the file, or go back to it with `None`, and rebuild thumbnail and
+/// preview
+pub async fn set_rotation(
+    client: &Client,
+    config: &Config,
+    id: i32,
+    rotation: Option<Rotate>,
+) -> Result<Entity> {
+    let mut entity = Entity::get(client, id)
+        .await
+        .ok_or(anyhow!("No such entity {}", id))?;
+    entity.rotation = rotation;
+    regenerate_entity(client, config, entity).await?;
+    Ok(Entity::get(client, id)
+        .await
+        .ok_or(anyhow!("No such entity {}", id))?)
+}
+
+/// Replace the edits of an entity, or go back to the original with no edits, and rebuild
+/// thumbnail and preview. If that fails the previous edits are put back, so the stored edits
+/// always match what's served
+pub async fn set_edits(
+    client: &Client,
+    config: &Config,
+    id: i32,
+    operations: &[EditOperation],
+) -> Result<Option<Edit>> {
+    let entity = Entity::get(client, id)
+        .await
+        .ok_or(anyhow!("No such entity {}", id))?;
+    let previous = Edit::operations_for(client, id).await?;
+    let edit = replace_edits(client, id, operations).await?;
+    if let Err(err) = regenerate_entity(client, config, entity).await {
+        replace_edits(client, id, &previous).await?;
+        return Err(err);
+    }
+    Ok(edit)
+}
+
+async fn replace_edits(
+    client: &Client,
+    id: i32,
+    operations: &[EditOperation],
+) -> Result<Option<Edit>> {
+    if operations.is_empty() {
+        Edit::delete(client, id).await?;
+        Ok(None)
+    } else {
+        Ok(Some(Edit::set(client, id, operations).await?))
+    }
+}
+
+pub async fn regenerate(
+    client: &Client,
+    config: &Config,
+    filter: &EntityFilter,
+    workers: usize,
+) -> Result<RegenerateSummary> {
+    let entities = Entity::list_filtered(client, filter).await?;
+    regenerate_entities(client, config, entities, workers).await
+}
+
+pub async fn regenerate_entities(
+    client: &Client,
+    config: &Config,
+    entities: Vec<Entity>,
+    workers: usize,
+) -> Result<RegenerateSummary> {
+    let mut results = stream::iter(entities)
+        .map(|entity| async move {
+            let id = entity.id;
+            (id, regenerate_entity(client, config, entity).await)
+        })
+        .buffer_unordered(workers.max(1));
+
+    let mut summary = RegenerateSummary::default();
+    while let Some((id, result)) = results.next().await {
+        match result {
+            Ok(()) => summary.regenerated.push(id),
+            Err(err) => summary.failed.push((id, err.to_string())),
+        }
+    }
+    Ok(summary)
+}
+
+/// Compute the BlurHash of media that doesn't have one yet, or of all media with `all`. Only the
+/// thumbnails are read, so this is a lot faster than regenerating
+pub async fn backfill_placeholders(
+    client: &Client,
+    all: bool,
+    workers: usize,
+) -> Result<RegenerateSummary> {
+    let entities: Vec<Entity> = Entity::list_desc(client).await?.try_collect().await?;
+
+    let mut results = stream::iter(entities.into_iter().filter(|e| all || e.blurhash.is_none()))
+        .map(|mut entity| async move {
+            let id = entity.id;
+            let thumbnail = entity.thumbnail_path.clone();
+            let result = match run_blocking(move || Ok(placeholder_hash(&thumbnail)?)).await {
+                Ok(blurhash) => {
+                    entity.blurhash = Some(blurhash);
+                    entity.save(client).await
+                }
+                Err(err) => Err(err),
+            };
+            (id, result)
+        })
+        .buffer_unordered(workers.max(1));
+
+    let mut summary = RegenerateSummary::default();
+    while let Some((id, result)) = results.next().await {
+        match result {
+            Ok(()) => summary.regenerated.push(id),
+            Err(err) => summary.failed.push((id, err.to_string())),
+        }
+    }
+    Ok(summary)
+}
+
+/// Read the metadata of an original again and store it, together with all of its raw metadata.
+/// The capture time of the entity is updated when the file has one, unless it has been corrected.
+/// The location is only filled in when it's missing as it may have been set by hand
+pub async fn refresh_entity_metadata(client: &Client, mut entity: Entity) -> Result<()> {
+    if entity.missing {
+        return Err(anyhow!("The original is missing").into());
+    }
+    let path = entity.path.clone();
+    let (metadata, raw_metadata) =
+        run_blocking(move || Ok((Metadata::from_file(&path)?, dump_metadata(&path)?))).await?;
+    EntityMetadata::set(client, entity.id, &metadata).await?;
+    RawMetadata::set(client, entity.id, &raw_metadata).await?;
+
+    if metadata.date_time.is_some() && !TimeCorrection::is_corrected(client, entity.id).await? {
+        entity.created = metadata.date_time;
+        entity.created_local = metadata.local_date_time;
+        entity.utc_offset = metadata.utc_offset;
+    }
+    if entity.location.is_none() {
+        entity.location = metadata.gps_location;
+    }
+    entity.save(client).await
+}
+
+pub async fn refresh_metadata(
+    client: &Client,
+    filter: &EntityFilter,
+    workers: usize,
+) -> Result<RegenerateSummary> {
+    let entities = Entity::list_filtered(client, filter).await?;
+
+    let mut results = stream::iter(entities)
+        .map(|entity| async move {
+            let id = entity.id;
+            (id, refresh_entity_metadata(client, entity).await)
+        })
+        .buffer_unordered(workers.max(1));
+
+    let mut summary = RegenerateSummary::default();
+    while let Some((id, result)) = results.next().await {
+        match result {
+            Ok(()) => summary.regenerated.push(id),
+            Err(err) => summary.failed.push((id, err.to_string())),
+        }
+    }
+    Ok(summary)
+}
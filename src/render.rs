@@ -0,0 +1,375 @@
+/*
+Image database, allows the user to host a database themselves,
+with the possibilities to tag and search after images.
+Copyright (C) 2020 Cybercom group AB, Sweden
+By Christoffer Dahl, Johanna Hultberg, Andreas Runfalk and Margareta Vi
+
+Image database is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, GenericImageView};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::config::{Fit, OutputFormat, RawSource, Render};
+use crate::edit::{fingerprint, EditOperation};
+use crate::model::Entity;
+use crate::thumbnail::{
+    crop_to_fill, file_type_from_path, open_edited_image, open_oriented_image, resize_to_fit,
+    save_image, MediaType,
+};
+
+/// Larger sizes than this are refused, as they would take a lot of time and memory to render
+const MAX_RENDER_SIZE: u32 = 8192;
+
+#[derive(Clone, Debug)]
+pub struct RenderParams {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub format: OutputFormat,
+}
+
+impl RenderParams {
+    pub fn new(
+        width: Option<u32>,
+        height: Option<u32>,
+        fit: Fit,
+        format: OutputFormat,
+    ) -> Result<Self> {
+        if width.is_none() && height.is_none() {
+            return Err(anyhow!("Width or height is required"));
+        }
+        for size in width.iter().chain(height.iter()) {
+            if *size == 0 || *size > MAX_RENDER_SIZE {
+                return Err(anyhow!(
+                    "Width and height must be between 1 and {}",
+                    MAX_RENDER_SIZE
+                ));
+            }
+        }
+        Ok(Self {
+            width,
+            height,
+            fit,
+            format,
+        })
+    }
+
+    /// The same entity rendered with the same parameters always gives the same key. The SHA-3 is
+    /// included so modified originals don't use stale renders
+    fn cache_key(&self, entity: &Entity, edits: &[EditOperation], quality: u8) -> Result<String> {
+        let size = |s: Option<u32>| s.map(|s| s.to_string()).unwrap_or("auto".into());
+        Ok(format!(
+            "{}_{}{}_{}x{}_{}_q{}.{}",
+            entity.id,
+            entity.sha3,
+            edits_suffix(entity, edits)?,
+            size(self.width),
+            size(self.height),
+            self.fit.as_str(),
+            quality,
+            self.format.extension(),
+        ))
+    }
+
+    /// Returns the size of the box to fit the image in. A missing width or height is computed
+    /// from the aspect ratio of the image
+    fn box_size(&self, img_width: u32, img_height: u32) -> (u32, u32) {
+        let scale = |size: u32, from: u32, to: u32| {
+            ((size as f32 * to as f32 / from as f32).round() as u32).max(1)
+        };
+        match (self.width, self.height) {
+            (Some(width), Some(height)) => (width, height),
+            (Some(width), None) => (width, scale(width, img_width, img_height)),
+            (None, Some(height)) => (scale(height, img_height, img_width), height),
+            (None, None) => (img_width, img_height),
+        }
+    }
+
+    /// Returns true if the image is large enough that it doesn't need to be scaled up
+    fn fits_in(&self, img_width: u32, img_height: u32) -> bool {
+        self.width.map_or(true, |width| width <= img_width)
+            && self.height.map_or(true, |height| height <= img_height)
+    }
+}
+
+struct CacheEntry {
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    /// Keys ordered by when they were last used
+    by_last_used: BTreeMap<u64, String>,
+    total_size: u64,
+    clock: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.by_last_used.remove(&entry.last_used);
+            entry.last_used = self.clock;
+            self.by_last_used.insert(self.clock, key.to_string());
+        }
+    }
+
+    fn insert(&mut self, key: String, size: u64) {
+        self.clock += 1;
+        if let Some(old) = self.entries.remove(&key) {
+            self.by_last_used.remove(&old.last_used);
+            self.total_size -= old.size;
+        }
+        self.by_last_used.insert(self.clock, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                size,
+                last_used: self.clock,
+            },
+        );
+        self.total_size += size;
+    }
+
+    /// Remove and return the least recently used key
+    fn pop_oldest(&mut self) -> Option<String> {
+        let (&last_used, _) = self.by_last_used.iter().next()?;
+        let key = self.by_last_used.remove(&last_used)?;
+        if let Some(entry) = self.entries.remove(&key) {
+            self.total_size -= entry.size;
+        }
+        Some(key)
+    }
+}
+
+/// Rendered images on disk, limited in size by removing the least recently used ones
+pub struct RenderCache {
+    dir: PathBuf,
+    max_size: u64,
+    state: Mutex<CacheState>,
+    /// Makes temporary file names unique, as the same key may be rendered by several requests
+    next_tmp: AtomicUsize,
+}
+
+impl RenderCache {
+    /// Open the cache, picking up whatever was rendered by earlier runs
+    pub fn open(config: &Render) -> Result<Self> {
+        fs::create_dir_all(&config.cache_dir)?;
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&config.cache_dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let key = entry.file_name().to_string_lossy().into_owned();
+            if key.starts_with('.') {
+                // Left behind by a render that didn't finish
+                fs::remove_file(entry.path())?;
+                continue;
+            }
+            // Files are only written when rendered, so this is the best guess of when they were
+            // last used
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((modified, key, metadata.len()));
+        }
+        files.sort();
+
+        let mut state = CacheState::default();
+        for (_, key, size) in files {
+            state.insert(key, size);
+        }
+
+        let cache = Self {
+            dir: config.cache_dir.clone(),
+            max_size: config.cache_size,
+            state: Mutex::new(state),
+            next_tmp: AtomicUsize::new(0),
+        };
+        cache.evict(None)?;
+        Ok(cache)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let data = fs::read(self.path(key)).ok()?;
+        self.state.lock().unwrap().touch(key);
+        Some(data)
+    }
+
+    /// Store a rendered image. `write` is given the path to write the file to
+    fn insert<F>(&self, key: &str, write: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce(&Path) -> Result<()>,
+    {
+        // Write to a temporary file first so concurrent requests never see a partial file
+        let tmp = self.next_tmp.fetch_add(1, Ordering::Relaxed);
+        let tmp_path = self
+            .dir
+            .join(format!(".{}.{}.{}.tmp", key, std::process::id(), tmp));
+        let written = write(&tmp_path).and_then(|_| Ok(fs::rename(&tmp_path, self.path(key))?));
+        if written.is_err() {
+            fs::remove_file(&tmp_path).ok();
+        }
+        written?;
+
+        let data = fs::read(self.path(key))?;
+        self.state
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), data.len() as u64);
+        self.evict(Some(key))?;
+        Ok(data)
+    }
+
+    /// Remove the least recently used files until the cache is small enough
+    fn evict(&self, keep: Option<&str>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while state.total_size > self.max_size {
+            let key = match state.pop_oldest() {
+                Some(key) => key,
+                None => break,
+            };
+            if Some(key.as_str()) == keep {
+                // The file that was just rendered is larger than the whole cache, it's still
+                // returned but not kept
+                fs::remove_file(self.path(&key))?;
+                break;
+            }
+            if let Err(err) = fs::remove_file(self.path(&key)) {
+                println!("Failed to remove {:?} from render cache: {}", key, err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Part of the cache key that tells rotated and edited renders apart, empty for media that is
+/// neither
+fn edits_suffix(entity: &Entity, edits: &[EditOperation]) -> Result<String> {
+    let mut suffix = String::new();
+    if let Some(ref rotation) = entity.rotation {
+        suffix.push_str(&format!("_r{}", rotation.degrees()));
+    }
+    if let Some(fingerprint) = fingerprint(edits)? {
+        suffix.push_str(&format!("_{}", fingerprint));
+    }
+    Ok(suffix)
+}
+
+/// Render an entity at the requested size and format. Results are cached, so rendering the same
+/// thing twice is cheap. Unlike thumbnails, crops are always centered
+pub fn render(
+    cache: &RenderCache,
+    entity: &Entity,
+    edits: &[EditOperation],
+    params: &RenderParams,
+    quality: u8,
+    raw_source: &RawSource,
+) -> Result<Vec<u8>> {
+    let key = params.cache_key(entity, edits, quality)?;
+    if let Some(data) = cache.get(&key) {
+        return Ok(data);
+    }
+
+    // The preview is a lot faster to decode than most originals, so only use the original when
+    // more pixels are needed. Videos only have the preview to go on. The preview already has the
+    // edits applied
+    let preview_type =
+        file_type_from_path(&entity.preview_path).ok_or(anyhow!("Unknown preview type"))?;
+    let mut img = open_oriented_image(&entity.preview_path, &preview_type, raw_source)?;
+    let (width, height) = img.dimensions();
+    if !params.fits_in(width, height) && !entity.missing {
+        if let Some(file_type) = file_type_from_path(&entity.path) {
+            if file_type.media_type() != MediaType::Video {
+                img = open_edited_image(
+                    &entity.path,
+                    &file_type,
+                    raw_source,
+                    entity.rotation.as_ref(),
+                    edits,
+                )?;
+            }
+        }
+    }
+
+    let (img_width, img_height) = img.dimensions();
+    let (width, height) = params.box_size(img_width, img_height);
+    let rendered = match params.fit {
+        Fit::Crop => crop_to_fill(&img, None, width, height),
+        Fit::Contain => resize_to_fit(&img, width, height),
+    };
+    cache.insert(&key, |path| {
+        save_image(&rendered, path, params.format, quality)
+    })
+}
+
+/// Open the original at full size with the edits applied
+pub fn open_edited_original(
+    entity: &Entity,
+    edits: &[EditOperation],
+    raw_source: &RawSource,
+) -> Result<DynamicImage> {
+    if entity.missing {
+        return Err(anyhow!("The original of {} is missing", entity.id));
+    }
+    let file_type = file_type_from_path(&entity.path).ok_or(anyhow!("Unknown file type"))?;
+    if file_type.media_type() == MediaType::Video {
+        return Err(anyhow!("Only images can be exported"));
+    }
+    open_edited_image(
+        &entity.path,
+        &file_type,
+        raw_source,
+        entity.rotation.as_ref(),
+        edits,
+    )
+}
+
+/// Render the original at full size with the edits applied. Like other renders the result is
+/// cached
+pub fn export(
+    cache: &RenderCache,
+    entity: &Entity,
+    edits: &[EditOperation],
+    format: OutputFormat,
+    quality: u8,
+    raw_source: &RawSource,
+) -> Result<Vec<u8>> {
+    let key = format!(
+        "{}_{}{}_full_q{}.{}",
+        entity.id,
+        entity.sha3,
+        edits_suffix(entity, edits)?,
+        quality,
+        format.extension()
+    );
+    if let Some(data) = cache.get(&key) {
+        return Ok(data);
+    }
+    let img = open_edited_original(entity, edits, raw_source)?;
+    cache.insert(&key, |path| save_image(&img, path, format, quality))
+}
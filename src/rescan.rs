@@ -0,0 +1,332 @@
+/*
+Image database, allows the user to host a database themselves,
+with the possibilities to tag and search after images.
+Copyright (C) 2020 Cybercom group AB, Sweden
+By Christoffer Dahl, Johanna Hultberg, Andreas Runfalk and Margareta Vi
+
+Image database is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::anyhow;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::iter::once;
+use std::path::{Path, PathBuf};
+use tokio_postgres::Client;
+use walkdir::WalkDir;
+
+use crate::config::Config;
+use crate::dump::dump_metadata;
+use crate::error::Result;
+use crate::hash::Sha3;
+use crate::import::populate_database;
+use crate::metadata::Metadata;
+use crate::model::{Derivative, Edit, Entity, EntityMetadata, RawMetadata};
+use crate::thumbnail::{create_thumbnail_in_place, file_type_from_path};
+use crate::util::{modified_time, run_blocking};
+
+#[derive(Debug)]
+pub enum Change {
+    /// The file is gone from its old path but a file with the same hash was found elsewhere
+    Moved { id: i32, from: PathBuf, to: PathBuf },
+    /// The file is gone and could not be found anywhere else
+    Deleted { id: i32, path: PathBuf },
+    /// A file that was previously flagged as missing is back
+    Restored { id: i32, path: PathBuf },
+    /// The file is still there but its contents have changed
+    Modified {
+        id: i32,
+        path: PathBuf,
+        size: u64,
+        sha3: Sha3,
+    },
+    /// The file has been written to but its contents are the same
+    Touched { id: i32, path: PathBuf },
+    /// The file is not imported yet
+    New { path: PathBuf },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Moved { id, from, to } => {
+                write!(f, "Moved (id {}): {:?} -> {:?}", id, from, to)
+            }
+            Change::Deleted { id, path } => write!(f, "Deleted (id {}): {:?}", id, path),
+            Change::Restored { id, path } => write!(f, "Restored (id {}): {:?}", id, path),
+            Change::Modified { id, path, .. } => write!(f, "Modified (id {}): {:?}", id, path),
+            Change::Touched { id, path } => write!(f, "Touched (id {}): {:?}", id, path),
+            Change::New { path } => write!(f, "New: {:?}", path),
+        }
+    }
+}
+
+/// Returns true if the file may have been changed since it was last read
+fn may_be_modified(entity: &Entity, path: &Path, size: u64) -> bool {
+    if size != entity.size {
+        return true;
+    }
+    match (modified_time(path), entity.modified) {
+        (Some(modified), Some(known)) => modified != known,
+        // Media imported before modification times were kept
+        (Some(modified), None) => modified > entity.uploaded,
+        (None, _) => false,
+    }
+}
+
+/// Walk the given roots and compare what's on disk with the entity table. Nothing is changed,
+/// pass the result to `apply_changes` to do that
+pub async fn rescan(
+    client: &Client,
+    config: &Config,
+    roots: &[PathBuf],
+    workers: usize,
+) -> Result<Vec<Change>> {
+    let entities: Vec<Entity> = Entity::list_desc(client).await?.try_collect().await?;
+    let by_path: HashMap<&PathBuf, &Entity> = entities.iter().map(|e| (&e.path, e)).collect();
+    let by_sha3: HashMap<&Sha3, &Entity> = entities.iter().map(|e| (&e.sha3, e)).collect();
+
+    // Files that must be hashed, together with the entity they are expected to belong to
+    let mut to_hash = Vec::new();
+    for entry in roots
+        .iter()
+        .map(|root| WalkDir::new(root).follow_links(true))
+        .flatten()
+    {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                println!("Failed to read: {}", err);
+                continue;
+            }
+        };
+        if entry.file_type().is_dir() || file_type_from_path(entry.path()).is_none() {
+            continue;
+        }
+
+        // Files may disappear while scanning, or be broken links
+        let checked = entry
+            .path()
+            .canonicalize()
+            .and_then(|path| Ok((entry.metadata()?.len(), path)));
+        let (size, path) = match checked {
+            Ok(checked) => checked,
+            Err(err) => {
+                println!("Failed to read {:?}: {}", entry.path(), err);
+                continue;
+            }
+        };
+        match by_path.get(&path) {
+            // Only hash known files that look changed, `verify` does a full check
+            Some(entity) if may_be_modified(entity, &path, size) => {
+                to_hash.push((path, Some(*entity)))
+            }
+            Some(_) => {}
+            None => to_hash.push((path, None)),
+        }
+    }
+
+    let mut hashed = stream::iter(to_hash)
+        .map(|(path, entity)| async move {
+            let hash_path = path.clone();
+            // The size is read again, as the file may have changed since it was listed
+            let hashed = run_blocking(move || {
+                Ok((
+                    Sha3::from_path_blocking(&hash_path)?,
+                    hash_path.metadata()?.len(),
+                ))
+            })
+            .await;
+            (path, entity, hashed)
+        })
+        .buffer_unordered(workers.max(1));
+
+    let mut changes = Vec::new();
+    let mut moved = HashSet::new();
+    while let Some((path, entity, hashed)) = hashed.next().await {
+        let (sha3, size) = match hashed {
+            Ok(hashed) => hashed,
+            Err(err) => {
+                println!("Failed to hash {:?}: {}", path, err);
+                continue;
+            }
+        };
+        match (entity, by_sha3.get(&sha3)) {
+            (Some(entity), _) => {
+                if sha3 != entity.sha3 {
+                    changes.push(Change::Modified {
+                        id: entity.id,
+                        size,
+                        path,
+                        sha3,
+                    });
+                } else {
+                    changes.push(Change::Touched {
+                        id: entity.id,
+                        path,
+                    });
+                }
+            }
+            (None, Some(entity)) => {
+                // The same file may exist in several places, only the first one found is used.
+                // Files in copied libraries are never used as they are, so media can't move there
+                if !entity.path.exists() && config.is_referenced(&path) && moved.insert(entity.id) {
+                    changes.push(Change::Moved {
+                        id: entity.id,
+                        from: entity.path.clone(),
+                        to: path,
+                    });
+                }
+            }
+            (None, None) => changes.push(Change::New { path }),
+        }
+    }
+
+    for entity in entities.iter().filter(|e| !moved.contains(&e.id)) {
+        let exists = entity.path.exists();
+        if !exists && !entity.missing {
+            changes.push(Change::Deleted {
+                id: entity.id,
+                path: entity.path.clone(),
+            });
+        } else if exists && entity.missing {
+            changes.push(Change::Restored {
+                id: entity.id,
+                path: entity.path.clone(),
+            });
+        }
+    }
+
+    Ok(changes)
+}
+
+pub async fn apply_changes(
+    client: &Client,
+    config: &Config,
+    changes: Vec<Change>,
+    workers: usize,
+) -> Result<()> {
+    let mut new_paths = Vec::new();
+    for change in changes {
+        if let Change::New { path } = change {
+            new_paths.push(path);
+            continue;
+        }
+        let description = change.to_string();
+        if let Err(err) = apply_change(client, config, change).await {
+            println!("Failed to apply {}: {}", description, err);
+        }
+    }
+
+    if !new_paths.is_empty() {
+        println!(
+            "{}",
+            populate_database(client, config, &new_paths, workers).await?
+        );
+    }
+    Ok(())
+}
+
+async fn apply_change(client: &Client, config: &Config, change: Change) -> Result<()> {
+    let id = match change {
+        Change::Moved { id, .. }
+        | Change::Deleted { id, .. }
+        | Change::Restored { id, .. }
+        | Change::Modified { id, .. }
+        | Change::Touched { id, .. } => id,
+        Change::New { .. } => unreachable!(),
+    };
+    let mut entity = Entity::get(client, id)
+        .await
+        .ok_or(anyhow!("No such entity {}", id))?;
+
+    match change {
+        Change::Moved { to, .. } => {
+            entity.modified = modified_time(&to);
+            entity.path = to;
+            entity.missing = false;
+        }
+        Change::Deleted { .. } => entity.missing = true,
+        Change::Restored { .. } => entity.missing = false,
+        Change::Touched { path, .. } => entity.modified = modified_time(&path),
+        Change::Modified {
+            path, size, sha3, ..
+        } => {
+            if let Some(other) = Entity::get_from_sha3(client, &sha3).await {
+                return Err(
+                    anyhow!("The new contents are already imported as {}", other.id).into(),
+                );
+            }
+            // Derivatives are named after the hash, so the old ones are no longer used once saved
+            let mut old_paths: HashSet<PathBuf> = Derivative::list_from_eids(client, &[id])
+                .await?
+                .into_iter()
+                .flat_map(|d| once(d.path.clone()).chain(d.alternative_paths()))
+                .collect();
+            old_paths.insert(entity.thumbnail_path.clone());
+            old_paths.insert(entity.preview_path.clone());
+            let modified = modified_time(&path);
+            let derivative_sha3 = sha3.clone();
+            let options = config.derivatives.clone();
+            let rotation = entity.rotation.clone();
+            let edits = Edit::operations_for(client, id).await?;
+            let (storage, metadata, raw_metadata) = run_blocking(move || {
+                let storage = create_thumbnail_in_place(
+                    &path,
+                    &derivative_sha3,
+                    &options,
+                    rotation.as_ref(),
+                    &edits,
+                )?;
+                Ok((
+                    storage,
+                    Metadata::from_file(&path).ok(),
+                    dump_metadata(&path).ok(),
+                ))
+            })
+            .await?;
+            Derivative::replace_for_eid(client, id, &storage.derivatives).await?;
+            if let Some(ref metadata) = metadata {
+                EntityMetadata::set(client, id, metadata).await?;
+            }
+            if let Some(ref raw_metadata) = raw_metadata {
+                RawMetadata::set(client, id, raw_metadata).await?;
+            }
+            // The file is read like at import, anything known about the old contents is stale
+            if let Some(metadata) = metadata {
+                entity.created = metadata.date_time;
+                entity.created_local = metadata.local_date_time;
+                entity.utc_offset = metadata.utc_offset;
+                entity.location = metadata.gps_location;
+            }
+            entity.thumbnail_path = storage.thumbnail;
+            entity.preview_path = storage.preview;
+            entity.blurhash = storage.blurhash;
+            entity.size = size;
+            entity.sha3 = sha3;
+            entity.modified = modified;
+            entity.save(client).await?;
+
+            for old_path in old_paths {
+                if let Err(err) = fs::remove_file(&old_path) {
+                    println!("Failed to remove {:?}: {}", old_path, err);
+                }
+            }
+            return Ok(());
+        }
+        Change::New { .. } => unreachable!(),
+    }
+    entity.save(client).await
+}
@@ -0,0 +1,103 @@
+/*
+Image database, allows the user to host a database themselves,
+with the possibilities to tag and search after images.
+Copyright (C) 2020 Cybercom group AB, Sweden
+By Christoffer Dahl, Johanna Hultberg, Andreas Runfalk and Margareta Vi
+
+Image database is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Fit, OutputFormat};
+use crate::hash::Sha3;
+use crate::thumbnail::FileType;
+
+pub const DEST_DIR: &str = "dest";
+
+/// Where the files for a piece of media are stored. Everything is named after the SHA-3 of the
+/// original and sharded into two levels of directories, so files with the same name never clash
+/// and no single directory grows too large, i.e. `dest/ab/cd/abcd...ef.jpg`
+#[derive(Clone, Debug)]
+pub struct StoragePaths {
+    pub original: PathBuf,
+    pub thumbnail: PathBuf,
+    pub preview: PathBuf,
+    /// Every derivative that was generated, including the thumbnail and preview
+    pub derivatives: Vec<DerivativeFile>,
+    /// BlurHash of the thumbnail
+    pub blurhash: Option<String>,
+}
+
+/// A resized copy of an original, generated according to a profile
+#[derive(Clone, Debug)]
+pub struct DerivativeFile {
+    pub profile: String,
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub fit: Fit,
+    /// The same derivative is also available in these formats, next to `path`
+    pub alternatives: Vec<OutputFormat>,
+}
+
+fn sharded_dir(hex: &str) -> PathBuf {
+    Path::new(DEST_DIR).join(&hex[0..2]).join(&hex[2..4])
+}
+
+impl StoragePaths {
+    pub fn new(sha3: &Sha3, file_type: &FileType) -> Self {
+        let hex = sha3.to_string();
+        let dir = sharded_dir(&hex);
+        Self {
+            original: dir.join(format!("{}.{}", hex, file_type.extension())),
+            thumbnail: dir.join(format!("{}_thumbnail.jpg", hex)),
+            preview: dir.join(format!("{}_preview.jpg", hex)),
+            derivatives: Vec::new(),
+            blurhash: None,
+        }
+    }
+
+    /// Path of the derivative generated for the given profile, i.e. `dest/ab/cd/abcd...ef_grid.jpg`
+    pub fn derivative(sha3: &Sha3, profile: &str, extension: &str) -> Result<PathBuf> {
+        // The name ends up in both file names and URLs
+        if profile.is_empty()
+            || !profile
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(anyhow!("Invalid profile name {:?}", profile));
+        }
+        let hex = sha3.to_string();
+        Ok(sharded_dir(&hex).join(format!("{}_{}.{}", hex, profile, extension)))
+    }
+
+    pub fn create_dirs(&self) -> Result<()> {
+        for path in &[&self.original, &self.thumbnail, &self.preview] {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the file name part of a user provided name, so it's safe to store and display
+pub fn original_name<P: AsRef<Path>>(path: P) -> String {
+    path.as_ref()
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
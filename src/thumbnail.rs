@@ -18,17 +18,30 @@ You should have received a copy of the GNU General Public License
 along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 use anyhow::{anyhow, Context, Result};
-use exif::Reader;
-use image::{load_from_memory, DynamicImage, GenericImageView, ImageBuffer};
+use exif::Exif;
+use image::{
+    AnimationDecoder, DynamicImage, GenericImageView, ImageBuffer, ImageFormat, ImageOutputFormat,
+};
+use libheif_rs::{
+    Channel, ColorSpace, CompressionFormat, EncoderQuality, HeifContext, Image, RgbChroma,
+};
 use std::convert::TryInto;
 use std::fs;
-use std::io::Write;
+use std::io::{self, BufReader, BufWriter, Read};
+use std::iter::once;
 use std::path::{Path, PathBuf};
-use std::process;
 use std::process::Command;
 
+use crate::color::{convert_to_srgb, read_color_profile};
+use crate::config::{Derivatives, Fit, OutputFormat, Profile, RawSource};
+use crate::edit::{
+    apply_edits, split_raw_adjustments, white_balance_multipliers, EditOperation, RawAdjustments,
+};
 use crate::face_detection::{calc_midpoint, face_detection, largest_bbox, Bbox};
-use crate::metadata::{Metadata, Rotate, TypeSpecific};
+use crate::hash::Sha3;
+use crate::metadata::{read_exif, Metadata, Rotate, TypeSpecific};
+use crate::raw::open_embedded_preview;
+use crate::storage::{DerivativeFile, StoragePaths};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum MediaType {
@@ -41,40 +54,162 @@ pub enum MediaType {
 pub enum FileType {
     Mp4,
     Mov,
+    Mkv,
+    Webm,
+    Avi,
+    /// 3GPP
+    Tgp,
+    /// MPEG transport stream, used by AVCHD cameras
+    Mts,
     Jpeg,
     Png,
+    Gif,
+    Tiff,
+    Webp,
+    Heic,
+    Avif,
     Cr2,
     Nef,
+    Nrw,
     Dng,
+    Arw,
+    Srf,
+    Sr2,
+    Raf,
+    Orf,
+    Rw2,
+    Pef,
+    Srw,
+    /// Hasselblad 3FR
+    Tfr,
+    Erf,
+    Kdc,
+    Dcr,
+    Mef,
+    Mrw,
+    Iiq,
 }
 
 impl FileType {
     pub fn media_type(&self) -> MediaType {
         match self {
-            FileType::Mp4 | FileType::Mov => MediaType::Video,
-            FileType::Jpeg | FileType::Png => MediaType::Image,
-            FileType::Cr2 | FileType::Nef | FileType::Dng => MediaType::RawImage,
+            FileType::Mp4
+            | FileType::Mov
+            | FileType::Mkv
+            | FileType::Webm
+            | FileType::Avi
+            | FileType::Tgp
+            | FileType::Mts => MediaType::Video,
+            FileType::Jpeg
+            | FileType::Png
+            | FileType::Gif
+            | FileType::Tiff
+            | FileType::Webp
+            | FileType::Heic
+            | FileType::Avif => MediaType::Image,
+            FileType::Cr2
+            | FileType::Nef
+            | FileType::Nrw
+            | FileType::Dng
+            | FileType::Arw
+            | FileType::Srf
+            | FileType::Sr2
+            | FileType::Raf
+            | FileType::Orf
+            | FileType::Rw2
+            | FileType::Pef
+            | FileType::Srw
+            | FileType::Tfr
+            | FileType::Erf
+            | FileType::Kdc
+            | FileType::Dcr
+            | FileType::Mef
+            | FileType::Mrw
+            | FileType::Iiq => MediaType::RawImage,
+        }
+    }
+
+    /// Extension used for the stored original
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FileType::Mp4 => "mp4",
+            FileType::Mov => "mov",
+            FileType::Mkv => "mkv",
+            FileType::Webm => "webm",
+            FileType::Avi => "avi",
+            FileType::Tgp => "3gp",
+            FileType::Mts => "mts",
+            FileType::Jpeg => "jpg",
+            FileType::Png => "png",
+            FileType::Gif => "gif",
+            FileType::Tiff => "tiff",
+            FileType::Webp => "webp",
+            FileType::Heic => "heic",
+            FileType::Avif => "avif",
+            FileType::Cr2 => "cr2",
+            FileType::Nef => "nef",
+            FileType::Nrw => "nrw",
+            FileType::Dng => "dng",
+            FileType::Arw => "arw",
+            FileType::Srf => "srf",
+            FileType::Sr2 => "sr2",
+            FileType::Raf => "raf",
+            FileType::Orf => "orf",
+            FileType::Rw2 => "rw2",
+            FileType::Pef => "pef",
+            FileType::Srw => "srw",
+            FileType::Tfr => "3fr",
+            FileType::Erf => "erf",
+            FileType::Kdc => "kdc",
+            FileType::Dcr => "dcr",
+            FileType::Mef => "mef",
+            FileType::Mrw => "mrw",
+            FileType::Iiq => "iiq",
         }
     }
-}
 
-fn add_suffix<T: AsRef<str>, U: AsRef<str>>(
-    img_path: &std::path::Path,
-    suffix: T,
-    extension: U,
-) -> Result<std::path::PathBuf> {
-    let file_name = img_path
-        .file_stem()
-        .ok_or(anyhow!("Unable to determine file base name"))?;
-    let mut os_string = std::ffi::OsString::from(file_name);
-    os_string.push(suffix.as_ref());
-    os_string.push(extension.as_ref());
-    let mut dest_path = img_path.to_path_buf();
-    dest_path.set_file_name(&os_string);
-    Ok(dest_path)
+    /// Returns true for formats that must be decoded using libheif
+    pub fn is_heif(&self) -> bool {
+        match self {
+            FileType::Heic | FileType::Avif => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true for raw formats that are plain TIFF files as far as magic bytes go
+    fn is_tiff_based(&self) -> bool {
+        match self {
+            FileType::Nef
+            | FileType::Nrw
+            | FileType::Dng
+            | FileType::Arw
+            | FileType::Srf
+            | FileType::Sr2
+            | FileType::Pef
+            | FileType::Srw
+            | FileType::Tfr
+            | FileType::Erf
+            | FileType::Kdc
+            | FileType::Dcr
+            | FileType::Mef
+            | FileType::Iiq => true,
+            _ => false,
+        }
+    }
+
+    /// Format to use when decoding with the image crate
+    fn image_format(&self) -> Option<ImageFormat> {
+        match self {
+            FileType::Jpeg => Some(ImageFormat::JPEG),
+            FileType::Png => Some(ImageFormat::PNG),
+            FileType::Gif => Some(ImageFormat::GIF),
+            FileType::Tiff => Some(ImageFormat::TIFF),
+            _ => None,
+        }
+    }
 }
 
-pub fn find_orientation(reader: &Reader) -> Option<Rotate> {
+pub fn find_orientation(reader: &Exif) -> Option<Rotate> {
     let exif_orientation = &reader.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?;
 
     match exif_orientation.value.get_uint(0)? {
@@ -86,7 +221,9 @@ pub fn find_orientation(reader: &Reader) -> Option<Rotate> {
     }
 }
 
-fn get_video_snapshot<P: AsRef<Path>>(orig_path: P) -> Result<DynamicImage> {
+/// Grab a frame from the middle of the video. ffmpeg rotates it according to the rotate tag,
+/// unless `no_autorotate` is set
+fn get_video_snapshot<P: AsRef<Path>>(orig_path: P, no_autorotate: bool) -> Result<DynamicImage> {
     let metadata = Metadata::from_file(&orig_path)?;
 
     let video_metadata = match metadata.type_specific {
@@ -101,9 +238,14 @@ fn get_video_snapshot<P: AsRef<Path>>(orig_path: P) -> Result<DynamicImage> {
         .to_str()
         .ok_or(anyhow!("Could not convert to str"))?;
 
-    let proc = Command::new("ffmpeg")
+    let mut command = Command::new("ffmpeg");
+    command
         .args(&["-loglevel", "-8"]) // silent, does not log anything
-        .args(&["-ss", &skip_to.to_string()]) // skip into half the video
+        .args(&["-ss", &skip_to.to_string()]); // skip into half the video
+    if no_autorotate {
+        command.arg("-noautorotate");
+    }
+    let proc = command
         .args(&["-i", orig_path]) // the path to the video
         .args(&["-frames:v", "1"]) // only save one frame
         .args(&["-f", "image2pipe"])
@@ -112,7 +254,8 @@ fn get_video_snapshot<P: AsRef<Path>>(orig_path: P) -> Result<DynamicImage> {
         .arg("-") // Output to stdout
         .output()
         .context("failed to extract thumbnail")?;
-    let (height, width) = if metadata.rotation == Some(Rotate::Zero)
+    let (height, width) = if no_autorotate
+        || metadata.rotation == Some(Rotate::Zero)
         || metadata.rotation == Some(Rotate::Cw180)
         || metadata.rotation == None
     {
@@ -125,22 +268,201 @@ fn get_video_snapshot<P: AsRef<Path>>(orig_path: P) -> Result<DynamicImage> {
     Ok(DynamicImage::ImageRgb8(buf))
 }
 
+/// Thumbnails are scaled down to this before computing the BlurHash, as it only has a few
+/// components anyway
+const PLACEHOLDER_SIZE: u32 = 32;
+
+/// Number of bytes read from the start of a file to detect its type
+const SNIFF_LEN: u64 = 4096;
+
+/// Detect the file type from the first bytes of a file
+fn sniff_file_type(data: &[u8]) -> Option<FileType> {
+    let at = |offset: usize, magic: &[u8]| data.get(offset..offset + magic.len()) == Some(magic);
+
+    if at(0, &[0xff, 0xd8, 0xff]) {
+        Some(FileType::Jpeg)
+    } else if at(0, b"\x89PNG\r\n\x1a\n") {
+        Some(FileType::Png)
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        Some(FileType::Gif)
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        Some(FileType::Webp)
+    } else if at(0, b"RIFF") && at(8, b"AVI ") {
+        Some(FileType::Avi)
+    } else if at(0, b"FUJIFILMCCD-RAW") {
+        Some(FileType::Raf)
+    } else if at(0, b"IIRO") || at(0, b"IIRS") || at(0, b"MMOR") {
+        Some(FileType::Orf)
+    } else if at(0, b"IIU\0") {
+        Some(FileType::Rw2)
+    } else if at(0, b"\0MRM") {
+        Some(FileType::Mrw)
+    } else if at(0, b"II*\0") && at(8, b"CR") {
+        Some(FileType::Cr2)
+    } else if at(0, b"II*\0") || at(0, b"MM\0*") {
+        Some(FileType::Tiff)
+    } else if at(4, b"ftyp") {
+        sniff_iso_bmff(data)
+    } else if at(4, b"moov") || at(4, b"mdat") || at(4, b"wide") || at(4, b"free") {
+        // QuickTime files from before the ftyp box was introduced
+        Some(FileType::Mov)
+    } else if at(0, &[0x1a, 0x45, 0xdf, 0xa3]) {
+        // The EBML header contains the doc type close to the start
+        let header = &data[..data.len().min(64)];
+        if header.windows(4).any(|w| w == b"webm") {
+            Some(FileType::Webm)
+        } else {
+            Some(FileType::Mkv)
+        }
+    } else if (at(0, &[0x47]) && at(188, &[0x47])) || (at(4, &[0x47]) && at(196, &[0x47])) {
+        // MPEG transport streams with 188 byte packets, or 192 byte packets as used by AVCHD
+        Some(FileType::Mts)
+    } else {
+        None
+    }
+}
+
+/// Tell ISO base media files apart using the major and compatible brands of the ftyp box
+fn sniff_iso_bmff(data: &[u8]) -> Option<FileType> {
+    let size = u32::from_be_bytes(data.get(0..4)?.try_into().ok()?) as usize;
+    let major_brand = data.get(8..12)?;
+    let compatible_brands = data.get(16..size.min(data.len())).unwrap_or(&[]);
+    let has_brand =
+        |brand: &[u8]| major_brand == brand || compatible_brands.chunks(4).any(|b| b == brand);
+
+    if has_brand(b"avif") || has_brand(b"avis") {
+        Some(FileType::Avif)
+    } else if [
+        b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1",
+    ]
+    .iter()
+    .any(|brand| major_brand == *brand)
+    {
+        Some(FileType::Heic)
+    } else if major_brand == b"qt  " {
+        Some(FileType::Mov)
+    } else if major_brand.starts_with(b"3g") {
+        Some(FileType::Tgp)
+    } else if major_brand.starts_with(b"iso")
+        || [
+            b"mp41", b"mp42", b"mp71", b"avc1", b"dash", b"M4V ", b"M4VH", b"M4VP", b"f4v ",
+            b"mmp4", b"MSNV", b"NDAS", b"XAVC",
+        ]
+        .iter()
+        .any(|brand| major_brand == *brand)
+    {
+        Some(FileType::Mp4)
+    } else {
+        // Other formats, such as CR3 and JPEG 2000, use the same container. Leave those to the
+        // extension
+        None
+    }
+}
+
+/// Determine the file type of `data`, falling back to the extension of `name` when the content
+/// isn't recognised
+pub fn file_type_from_bytes<P: AsRef<Path>>(name: P, data: &[u8]) -> Option<FileType> {
+    let from_extension = file_type_from_extension(name);
+    match sniff_file_type(data) {
+        // Most raw formats can't be told apart from TIFF without parsing it, so trust the
+        // extension for those
+        Some(FileType::Tiff)
+            if from_extension
+                .as_ref()
+                .map_or(false, FileType::is_tiff_based) =>
+        {
+            from_extension
+        }
+        Some(file_type) => Some(file_type),
+        None => from_extension,
+    }
+}
+
+/// Determine the file type from the contents of the file, falling back to the extension
 pub fn file_type_from_path<P: AsRef<Path>>(path: P) -> Option<FileType> {
+    let mut header = Vec::new();
+    if let Ok(file) = fs::File::open(&path) {
+        // A file we can't read is identified by its extension, it will fail later on anyway
+        let _ = file.take(SNIFF_LEN).read_to_end(&mut header);
+    }
+    file_type_from_bytes(path, &header)
+}
+
+/// Print a warning if the extension of `name` says something else than the contents
+pub fn warn_on_extension_mismatch<P: AsRef<Path>>(name: P, file_type: &FileType) {
+    if let Some(from_extension) = file_type_from_extension(&name) {
+        if &from_extension != file_type {
+            println!(
+                "Warning: {:?} has the extension of {:?} but contains {:?}",
+                name.as_ref(),
+                from_extension,
+                file_type
+            );
+        }
+    }
+}
+
+pub fn file_type_from_extension<P: AsRef<Path>>(path: P) -> Option<FileType> {
     let ext = path.as_ref().extension()?.to_str()?;
     match ext.to_ascii_lowercase().as_str() {
         "jpg" | "jpeg" => Some(FileType::Jpeg),
         "png" => Some(FileType::Png),
+        "gif" => Some(FileType::Gif),
+        "tif" | "tiff" => Some(FileType::Tiff),
+        "webp" => Some(FileType::Webp),
+        "heic" | "heif" => Some(FileType::Heic),
+        "avif" => Some(FileType::Avif),
         "cr2" => Some(FileType::Cr2),
         "nef" => Some(FileType::Nef),
+        "nrw" => Some(FileType::Nrw),
+        "arw" => Some(FileType::Arw),
+        "srf" => Some(FileType::Srf),
+        "sr2" => Some(FileType::Sr2),
+        "raf" => Some(FileType::Raf),
+        "orf" => Some(FileType::Orf),
+        "rw2" => Some(FileType::Rw2),
+        "pef" => Some(FileType::Pef),
+        "srw" => Some(FileType::Srw),
+        "3fr" => Some(FileType::Tfr),
+        "erf" => Some(FileType::Erf),
+        "kdc" => Some(FileType::Kdc),
+        "dcr" => Some(FileType::Dcr),
+        "mef" => Some(FileType::Mef),
+        "mrw" => Some(FileType::Mrw),
+        "iiq" => Some(FileType::Iiq),
         "mov" => Some(FileType::Mov),
         "mp4" => Some(FileType::Mp4),
+        "mkv" => Some(FileType::Mkv),
+        "webm" => Some(FileType::Webm),
+        "avi" => Some(FileType::Avi),
+        "3gp" | "3g2" => Some(FileType::Tgp),
+        "mts" | "m2ts" => Some(FileType::Mts),
         "dng" => Some(FileType::Dng),
         _ => None,
     }
 }
 
-pub fn open_raw_image<P: AsRef<Path>>(path: P) -> Result<DynamicImage> {
-    let srgb_img = imagepipe::simple_decode_8bit(path, 0, 0).map_err(|e| anyhow!("{}", e))?;
+pub fn open_raw_image<P: AsRef<Path>>(
+    path: P,
+    source: &RawSource,
+    adjustments: &RawAdjustments,
+) -> Result<DynamicImage> {
+    // Exposure and white balance can only be changed when developing
+    if source == &RawSource::Embedded && adjustments.is_neutral() {
+        if let Ok(img) = open_embedded_preview(&path) {
+            return Ok(img);
+        }
+    }
+
+    // The pipeline always outputs sRGB
+    let mut pipeline =
+        imagepipe::Pipeline::new_from_file(path, 0, 0, false).map_err(|e| anyhow!("{}", e))?;
+    pipeline.ops.basecurve.exposure += adjustments.exposure;
+    let multipliers = white_balance_multipliers(adjustments.temperature, adjustments.tint);
+    for (coeff, multiplier) in pipeline.ops.tolab.wb_coeffs.iter_mut().zip(&multipliers) {
+        *coeff *= multiplier;
+    }
+    let srgb_img = pipeline.output_8bit(None).map_err(|e| anyhow!("{}", e))?;
     let buf = ImageBuffer::from_raw(
         srgb_img.width.try_into()?,
         srgb_img.height.try_into()?,
@@ -150,86 +472,459 @@ pub fn open_raw_image<P: AsRef<Path>>(path: P) -> Result<DynamicImage> {
     Ok(DynamicImage::ImageRgb8(buf))
 }
 
-pub fn copy_and_create_thumbnail<P: AsRef<Path>>(path: P) -> Result<(PathBuf, PathBuf, PathBuf)> {
+/// Rotation and mirroring stored in the container are applied while decoding, unless
+/// `ignore_transformations` is set
+pub fn open_heif_image<P: AsRef<Path>>(
+    path: P,
+    ignore_transformations: bool,
+) -> Result<DynamicImage> {
+    let path = path
+        .as_ref()
+        .to_str()
+        .ok_or(anyhow!("Could not convert to str"))?;
+    let ctx = HeifContext::read_from_file(path)?;
+    let handle = ctx.primary_image_handle()?;
+
+    let img = handle.decode(ColorSpace::Rgb(RgbChroma::Rgb), ignore_transformations)?;
+    let plane = img
+        .planes()
+        .interleaved
+        .ok_or(anyhow!("Failed to decode HEIF image"))?;
+
+    // Rows may be padded, so only copy the actual pixels
+    let row_len = plane.width as usize * 3;
+    let mut data = Vec::with_capacity(row_len * plane.height as usize);
+    for row in plane.data.chunks(plane.stride) {
+        data.extend_from_slice(&row[..row_len]);
+    }
+    let buf = ImageBuffer::from_raw(plane.width, plane.height, data)
+        .ok_or(anyhow!("Failed to convert HEIF to image"))?;
+    Ok(DynamicImage::ImageRgb8(buf))
+}
+
+pub fn heif_dimensions<P: AsRef<Path>>(path: P) -> Result<(u32, u32)> {
+    let path = path
+        .as_ref()
+        .to_str()
+        .ok_or(anyhow!("Could not convert to str"))?;
+    let handle = HeifContext::read_from_file(path)?.primary_image_handle()?;
+    Ok((handle.width(), handle.height()))
+}
+
+pub fn copy_and_create_thumbnail<P: AsRef<Path>>(
+    path: P,
+    sha3: &Sha3,
+    options: &Derivatives,
+) -> Result<StoragePaths> {
+    let file_type = file_type_from_path(path.as_ref()).ok_or(anyhow!("Unknown file type"))?;
+    let mut storage = StoragePaths::new(sha3, &file_type);
+    storage.create_dirs()?;
+    fs::copy(&path, &storage.original)?;
+    if let Err(e) = create_derivatives(&mut storage, &file_type, sha3, options, None, &[]) {
+        remove_stored_files(sha3, options, Some(&storage.original));
+        return Err(e);
+    }
+    Ok(storage)
+}
+
+/// Like `copy_and_create_thumbnail` but the original is left where it is and the given rotation
+/// and edits are applied
+pub fn create_thumbnail_in_place<P: AsRef<Path>>(
+    path: P,
+    sha3: &Sha3,
+    options: &Derivatives,
+    rotation: Option<&Rotate>,
+    edits: &[EditOperation],
+) -> Result<StoragePaths> {
     let file_type = file_type_from_path(path.as_ref()).ok_or(anyhow!("Unknown file type"))?;
-    let (img, rotation) = match file_type.media_type() {
+    let mut storage = StoragePaths::new(sha3, &file_type);
+    storage.original = path.as_ref().canonicalize()?;
+    storage.create_dirs()?;
+    create_derivatives(&mut storage, &file_type, sha3, options, rotation, edits)?;
+    Ok(storage)
+}
+
+pub fn copy_and_create_thumbnail_bytes(
+    file_name: &str,
+    data: &[u8],
+    sha3: &Sha3,
+    options: &Derivatives,
+) -> Result<StoragePaths> {
+    let file_type = file_type_from_bytes(file_name, data).ok_or(anyhow!("Unknown file type"))?;
+    let mut storage = StoragePaths::new(sha3, &file_type);
+    storage.create_dirs()?;
+    fs::write(&storage.original, data)?;
+    if let Err(e) = create_derivatives(&mut storage, &file_type, sha3, options, None, &[]) {
+        remove_stored_files(sha3, options, Some(&storage.original));
+        return Err(e);
+    }
+    Ok(storage)
+}
+
+/// Remove every derivative that may have been created for `sha3`, together with the copy of the
+/// original if given. Used when an import fails, so nothing is left behind as an orphan
+pub fn remove_stored_files(sha3: &Sha3, options: &Derivatives, original: Option<&Path>) {
+    let mut paths: Vec<PathBuf> = original.map(Path::to_path_buf).into_iter().collect();
+    for profile in options.profiles() {
+        let extensions = once(profile.format.extension())
+            .chain(options.extra_formats.iter().map(OutputFormat::extension))
+            // Animated GIFs are copied as they are
+            .chain(once("gif"));
+        for extension in extensions {
+            if let Ok(path) = StoragePaths::derivative(sha3, &profile.name, extension) {
+                paths.push(path);
+            }
+        }
+    }
+    for path in paths {
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                println!("Failed to remove {:?}: {}", path, err)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Open an image using the detected file type, as the image crate only looks at the extension
+pub fn open_image<P: AsRef<Path>>(path: P, file_type: &FileType) -> Result<DynamicImage> {
+    if file_type == &FileType::Webp {
+        return open_webp(path);
+    }
+    let format = file_type.image_format().ok_or(anyhow!(
+        "{:?} is not supported by the image crate",
+        file_type
+    ))?;
+    Ok(image::load(BufReader::new(fs::File::open(path)?), format)?)
+}
+
+/// The WebP decoder of the image crate only handles lossy images without alpha, and returns them
+/// as grayscale, so libwebp is used instead
+fn open_webp<P: AsRef<Path>>(path: P) -> Result<DynamicImage> {
+    let data = fs::read(path)?;
+    // Animations are flagged in the extended header, which libwebp refuses to decode as a still
+    if data.get(12..16) == Some(b"VP8X") && data.get(20).map_or(false, |flags| flags & 0x02 != 0) {
+        return Err(anyhow!("Animated WebP is not supported"));
+    }
+    let decoded = webp::Decoder::new(&data)
+        .decode()
+        .ok_or(anyhow!("Failed to decode WebP"))?;
+    let (width, height) = (decoded.width(), decoded.height());
+    let img = if decoded.is_alpha() {
+        ImageBuffer::from_raw(width, height, decoded.to_vec()).map(DynamicImage::ImageRgba8)
+    } else {
+        ImageBuffer::from_raw(width, height, decoded.to_vec()).map(DynamicImage::ImageRgb8)
+    };
+    img.ok_or(anyhow!("Invalid WebP image size"))
+}
+
+fn is_animated_gif<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let decoder = image::gif::Decoder::new(fs::File::open(path)?)?;
+    Ok(decoder.into_frames().take(2).count() > 1)
+}
+
+/// Create thumbnail, preview and the other configured derivatives from the stored original
+fn create_derivatives(
+    storage: &mut StoragePaths,
+    file_type: &FileType,
+    sha3: &Sha3,
+    options: &Derivatives,
+    rotation: Option<&Rotate>,
+    edits: &[EditOperation],
+) -> Result<()> {
+    let path = &storage.original;
+    let img = open_edited_image(path, file_type, &options.raw_source, rotation, edits)?;
+    // Edits can't be applied to the animation, so edited GIFs get a still preview
+    let animated_gif = file_type == &FileType::Gif
+        && rotation.is_none()
+        && edits.is_empty()
+        && is_animated_gif(path)?;
+
+    // Face detection is slow, so only run it once and only if something is cropped
+    let profiles = options.profiles();
+    let face = if profiles.iter().any(|p| p.fit == Fit::Crop) {
+        find_largest_face(&img)
+    } else {
+        None
+    };
+
+    let mut derivatives = Vec::new();
+    let mut blurhash = None;
+    for profile in profiles {
+        let derivative = if animated_gif && profile.name == Derivatives::PREVIEW {
+            // Keep the animation, the other derivatives use the first frame
+            let derivative_path = StoragePaths::derivative(sha3, &profile.name, "gif")?;
+            fs::copy(path, &derivative_path)?;
+            let (width, height) = img.dimensions();
+            DerivativeFile {
+                profile: profile.name,
+                path: derivative_path,
+                width,
+                height,
+                fit: Fit::Contain,
+                alternatives: Vec::new(),
+            }
+        } else {
+            let (derivative, resized) =
+                create_derivative(&img, face, sha3, profile, &options.extra_formats)?;
+            // The placeholder is only a nicety, so media is imported without one if it fails
+            if derivative.profile == Derivatives::THUMBNAIL {
+                blurhash = image_placeholder_hash(&resized).ok();
+            }
+            derivative
+        };
+
+        if derivative.profile == Derivatives::THUMBNAIL {
+            storage.thumbnail = derivative.path.clone();
+        } else if derivative.profile == Derivatives::PREVIEW {
+            storage.preview = derivative.path.clone();
+        }
+        derivatives.push(derivative);
+    }
+    storage.derivatives = derivatives;
+    storage.blurhash = blurhash;
+    Ok(())
+}
+
+/// BlurHash of a thumbnail, which the frontend shows while the thumbnail itself loads
+pub fn placeholder_hash<P: AsRef<Path>>(thumbnail: P) -> Result<String> {
+    let file_type = file_type_from_path(&thumbnail).ok_or(anyhow!("Unknown file type"))?;
+    image_placeholder_hash(&open_image(&thumbnail, &file_type)?)
+}
+
+/// BlurHash of a thumbnail that is already open
+fn image_placeholder_hash(thumbnail: &DynamicImage) -> Result<String> {
+    let img = thumbnail.thumbnail(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE);
+    let (width, height) = img.dimensions();
+    let (components_x, components_y) = if width >= height { (4, 3) } else { (3, 4) };
+    Ok(blurhash::encode(
+        components_x,
+        components_y,
+        width,
+        height,
+        &img.to_rgba().into_vec(),
+    ))
+}
+
+/// Open any supported file as an image that is the right way up. Videos give a frame from the
+/// middle of the video
+pub fn open_oriented_image<P: AsRef<Path>>(
+    path: P,
+    file_type: &FileType,
+    raw_source: &RawSource,
+) -> Result<DynamicImage> {
+    open_adjusted_image(
+        path,
+        file_type,
+        raw_source,
+        None,
+        &RawAdjustments::default(),
+    )
+}
+
+/// Like `open_oriented_image` with the edits applied. A given rotation is used instead of the one
+/// stored in the file. Exposure and white balance of raw images are set on the raw pipeline rather
+/// than applied afterwards
+pub fn open_edited_image<P: AsRef<Path>>(
+    path: P,
+    file_type: &FileType,
+    raw_source: &RawSource,
+    rotation: Option<&Rotate>,
+    edits: &[EditOperation],
+) -> Result<DynamicImage> {
+    let (adjustments, edits) = if file_type.media_type() == MediaType::RawImage {
+        split_raw_adjustments(edits)
+    } else {
+        (RawAdjustments::default(), edits.to_vec())
+    };
+    let img = open_adjusted_image(path, file_type, raw_source, rotation, &adjustments)?;
+    apply_edits(img, &edits)
+}
+
+fn open_adjusted_image<P: AsRef<Path>>(
+    path: P,
+    file_type: &FileType,
+    raw_source: &RawSource,
+    rotation: Option<&Rotate>,
+    adjustments: &RawAdjustments,
+) -> Result<DynamicImage> {
+    let path = path.as_ref();
+    let (img, detected) = match file_type.media_type() {
+        // libheif has already rotated the image, so EXIF orientation must not be applied again
+        MediaType::Image if file_type.is_heif() => (
+            open_heif_image(path, rotation.is_some()).context("failed to open HEIF image")?,
+            Rotate::Zero,
+        ),
         MediaType::Image => (
-            image::open(path.as_ref()).context("failed to open image")?,
+            open_image(path, file_type).context("failed to open image")?,
             {
-                let file = fs::File::open(&path).unwrap();
-                exif::Reader::new(&mut std::io::BufReader::new(&file))
+                let file = fs::File::open(path)?;
+                exif::Reader::new()
+                    .read_from_container(&mut std::io::BufReader::new(&file))
                     .map(|x| find_orientation(&x).unwrap_or(Rotate::Zero))
                     .unwrap_or(Rotate::Zero)
             },
         ),
         MediaType::RawImage => (
-            open_raw_image(path.as_ref()).context("failed to open raw image")?,
-            Rotate::Zero,
+            open_raw_image(path, raw_source, adjustments).context("failed to open raw image")?,
+            read_exif(path)
+                .ok()
+                .and_then(|x| find_orientation(&x))
+                .unwrap_or(Rotate::Zero),
         ),
-        MediaType::Video => (get_video_snapshot(path.as_ref())?, Rotate::Zero),
+        MediaType::Video => (get_video_snapshot(path, rotation.is_some())?, Rotate::Zero),
     };
 
-    let file_name = path.as_ref().file_stem().unwrap();
-    let img = rotate_image(&img, rotation)?;
-
-    fs::create_dir_all("dest")?;
-    let dest_path = Path::new("dest");
-    let copied_orig = dest_path.join(path.as_ref().file_name().unwrap());
-    fs::copy(&path, &copied_orig)?;
-
-    let preview_path = add_suffix(&dest_path.join(file_name), "_preview", ".jpg")?;
-    create_preview(&img, &preview_path)?;
-
-    let thumbnail = create_thumbnail_image(img)?;
-    let thumbnail_path = add_suffix(&dest_path.join(file_name), "_thumbnail", ".jpg")?;
-    thumbnail.save(&thumbnail_path)?;
-
-    Ok((copied_orig, thumbnail_path, preview_path))
-}
-
-fn create_thumbnail_image(mut img: image::DynamicImage) -> Result<DynamicImage> {
-    // Detect faces to determine where to optimally crop the image
-    let detection = face_detection(&img);
-    let thumbnail: image::DynamicImage;
-    match detection {
-        Ok(faces) => {
-            if faces.len() == 0 {
-                thumbnail = img.resize_to_fill(300, 200, image::FilterType::CatmullRom);
-            } else {
-                // Look for the largest face in the image and try to preserve it in the thumbnail
-                let largest_bbox = largest_bbox(faces);
-                let (start_x, start_y, new_width, new_height) =
-                    calc_new_measurements(&img, largest_bbox);
-                let tmp = img.crop(start_x, start_y, new_width, new_height);
-                thumbnail = tmp.resize_exact(300, 200, image::FilterType::CatmullRom);
-            }
+    // Raw images are developed to sRGB, and their embedded previews are converted when opened
+    let profile = match file_type.media_type() {
+        MediaType::Image => read_color_profile(path, file_type),
+        _ => None,
+    };
+    let img = match profile {
+        Some(profile) => convert_to_srgb(img, &profile),
+        None => img,
+    };
+
+    // JPEG has neither alpha nor 16-bit support, which PNG, TIFF and friends may use
+    let rotation = rotation.cloned().unwrap_or(detected);
+    let img = DynamicImage::ImageRgb8(rotate_image(&img, rotation)?.to_rgb());
+    Ok(img)
+}
+
+/// Crops are centered when there are no faces, or when looking for them fails
+fn find_largest_face(img: &DynamicImage) -> Option<Bbox> {
+    match face_detection(img) {
+        Ok(faces) if faces.is_empty() => None,
+        Ok(faces) => Some(largest_bbox(faces)),
+        Err(err) => {
+            println!("Face detection failed: {}", err);
+            None
         }
-        Err(_) => process::exit(1),
     }
-    Ok(thumbnail)
 }
 
-fn create_preview<P: AsRef<Path>>(img: &image::DynamicImage, preview_path: &P) -> Result<()> {
-    let (width, height) = img.dimensions();
-    let mut new_width = 4096;
-    let mut new_height = 2160;
-    if width > new_width || height > new_height {
-        if height > width {
+fn create_derivative(
+    img: &DynamicImage,
+    face: Option<Bbox>,
+    sha3: &Sha3,
+    profile: Profile,
+    extra_formats: &[OutputFormat],
+) -> Result<(DerivativeFile, DynamicImage)> {
+    let resized = match profile.fit {
+        Fit::Crop => crop_to_fill(img, face, profile.width, profile.height),
+        Fit::Contain => resize_to_fit(img, profile.width, profile.height),
+    };
+    let path = StoragePaths::derivative(sha3, &profile.name, profile.format.extension())?;
+    save_image(&resized, &path, profile.format, profile.quality)?;
+
+    let mut alternatives = Vec::new();
+    for &format in extra_formats {
+        if format != profile.format && !alternatives.contains(&format) {
+            save_image(
+                &resized,
+                path.with_extension(format.extension()),
+                format,
+                profile.quality,
+            )?;
+            alternatives.push(format);
+        }
+    }
+
+    let (width, height) = resized.dimensions();
+    let derivative = DerivativeFile {
+        profile: profile.name,
+        path,
+        width,
+        height,
+        fit: profile.fit,
+        alternatives,
+    };
+    Ok((derivative, resized))
+}
+
+pub fn save_image<P: AsRef<Path>>(
+    img: &DynamicImage,
+    path: P,
+    format: OutputFormat,
+    quality: u8,
+) -> Result<()> {
+    let quality = quality.max(1).min(100);
+    let format = match format {
+        OutputFormat::Jpeg => ImageOutputFormat::JPEG(quality),
+        OutputFormat::Png => ImageOutputFormat::PNG,
+        OutputFormat::Webp => {
+            let rgb = img.to_rgb();
+            let data =
+                webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height()).encode(quality.into());
+            fs::write(path, &*data)?;
+            return Ok(());
+        }
+        OutputFormat::Avif => return save_avif(img, path, quality),
+    };
+    let mut writer = BufWriter::new(fs::File::create(path)?);
+    img.write_to(&mut writer, format)?;
+    Ok(())
+}
+
+fn save_avif<P: AsRef<Path>>(img: &DynamicImage, path: P, quality: u8) -> Result<()> {
+    let rgb = img.to_rgb();
+    let (width, height) = rgb.dimensions();
+    let mut image = Image::new(width, height, ColorSpace::Rgb(RgbChroma::Rgb))?;
+    image.create_plane(Channel::Interleaved, width, height, 8)?;
+    {
+        let planes = image.planes_mut();
+        let plane = planes
+            .interleaved
+            .ok_or(anyhow!("Failed to create image plane"))?;
+        let row_len = width as usize * 3;
+        for (y, row) in rgb.chunks(row_len).enumerate() {
+            plane.data[y * plane.stride..y * plane.stride + row_len].copy_from_slice(row);
+        }
+    }
+
+    let mut context = HeifContext::new()?;
+    let mut encoder = context.encoder_for_format(CompressionFormat::Av1)?;
+    encoder.set_quality(EncoderQuality::Lossy(quality))?;
+    context.encode_image(&image, &mut encoder, None)?;
+    context.write_to_file(
+        path.as_ref()
+            .to_str()
+            .ok_or(anyhow!("Could not convert to str"))?,
+    )?;
+    Ok(())
+}
+
+/// Scale and crop the image to exactly the given size. If there is a face it's kept in view
+pub fn crop_to_fill(
+    img: &DynamicImage,
+    face: Option<Bbox>,
+    width: u32,
+    height: u32,
+) -> DynamicImage {
+    match face {
+        None => img.resize_to_fill(width, height, image::FilterType::CatmullRom),
+        Some(bbox) => {
             let aspect_ratio = width as f32 / height as f32;
-            new_width = (2160 as f32 * aspect_ratio).ceil() as u32;
-        } else {
-            let aspect_ratio = height as f32 / width as f32;
-            new_height = (4096 as f32 * aspect_ratio).ceil() as u32;
+            let (start_x, start_y, new_width, new_height) =
+                calc_new_measurements(img, bbox, aspect_ratio);
+            let mut img = img.clone();
+            img.crop(start_x, start_y, new_width, new_height)
+                .resize_exact(width, height, image::FilterType::CatmullRom)
         }
-        let preview = img.resize_exact(new_width, new_height, image::FilterType::CatmullRom);
-        preview.save(&preview_path)?;
+    }
+}
+
+/// Scale the image down to fit within the given size, keeping its aspect ratio
+pub fn resize_to_fit(img: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let (img_width, img_height) = img.dimensions();
+    if img_width > width || img_height > height {
+        img.resize(width, height, image::FilterType::CatmullRom)
     } else {
-        img.save(&preview_path)?;
+        img.clone()
     }
-    Ok(())
 }
 
-fn rotate_image(img: &DynamicImage, rotation: Rotate) -> Result<DynamicImage> {
+pub fn rotate_image(img: &DynamicImage, rotation: Rotate) -> Result<DynamicImage> {
     let rotated = match rotation {
         Rotate::Zero => img.clone(),
         Rotate::Cw90 => img.rotate90(),
@@ -239,78 +934,29 @@ fn rotate_image(img: &DynamicImage, rotation: Rotate) -> Result<DynamicImage> {
     Ok(rotated)
 }
 
-pub fn copy_and_create_thumbnail_bytes(
-    file_name: &str,
-    data: &Vec<u8>,
-) -> Result<(PathBuf, PathBuf, PathBuf)> {
-    let slice = data.as_slice();
-
-    fs::create_dir_all("dest")?;
-    let dest_path = Path::new("dest");
-    let copied_orig = dest_path.join(file_name);
-    let file_path_new = format!("./dest/{}", &file_name);
-    let mut file = std::fs::File::create(file_path_new.as_str())?;
-    file.write_all(slice)?;
-
-    let file_type = file_type_from_path(file_name).ok_or(anyhow!("Unknown file type"))?;
-    let (img, rotation) = match file_type.media_type() {
-        MediaType::Image => (
-            load_from_memory(slice)?,
-            exif::Reader::new(&mut std::io::BufReader::new(slice))
-                .map(|x| find_orientation(&x).unwrap_or(Rotate::Zero))
-                .unwrap_or(Rotate::Zero),
-        ),
-        MediaType::RawImage => (
-            open_raw_image(file_path_new).context("failed to open raw image")?,
-            Rotate::Zero,
-        ),
-        MediaType::Video => (
-            match get_video_snapshot(&file_path_new) {
-                Ok(o) => o,
-                Err(e) => {
-                    std::fs::remove_file(file_path_new.as_str())?;
-                    return Err(e.into());
-                }
-            },
-            Rotate::Zero,
-        ),
-    };
-
-    let img = rotate_image(&img, rotation)?;
-
-    let preview_tmp = format!("{}{}{}", file_name, "_preview", ".jpg");
-    let preview_path = dest_path.join(preview_tmp.as_str());
-
-    create_preview(&img, &preview_path)?;
-
-    let thumbnail = create_thumbnail_image(img)?;
-
-    let thumbnail_tmp = format!("{}{}{}", file_name, "_thumbnail", ".jpg");
-    let thumbnail_path = dest_path.join(thumbnail_tmp.as_str());
-    thumbnail.save(&thumbnail_path)?;
-
-    Ok((copied_orig, thumbnail_path, preview_path))
-}
-
 // return a tuple with (start_x, start_y, width, height)
-fn calc_new_measurements(img: &image::DynamicImage, bbox: Bbox) -> (u32, u32, u32, u32) {
+fn calc_new_measurements(
+    img: &image::DynamicImage,
+    bbox: Bbox,
+    target_aspect_ratio: f32,
+) -> (u32, u32, u32, u32) {
     let (width, height) = img.dimensions();
     let (x_mid, y_mid) = calc_midpoint(bbox);
     let aspect_ratio = width as f32 / height as f32;
-    if aspect_ratio as f32 == 1.5 {
-        // already 3:2 format
+    if aspect_ratio == target_aspect_ratio {
+        // already in the right format
         return (0, 0, width, height);
-    } else if aspect_ratio as f32 > 1.5 {
-        let new_width = (height as f32 * 1.5).ceil() as u32;
-        let diff = x_mid as i32 - new_width as i32 / 2;
+    } else if aspect_ratio > target_aspect_ratio {
+        let new_width = ((height as f32 * target_aspect_ratio).ceil() as u32).min(width);
+        let diff = (x_mid as i32 - new_width as i32 / 2).min((width - new_width) as i32);
         if diff >= 0 {
             return (diff as u32, 0, new_width, height);
         } else {
             return (0, 0, new_width, height);
         }
     } else {
-        let new_height = (width as f32 / 1.5).ceil() as u32;
-        let diff = y_mid as i32 - new_height as i32 / 2;
+        let new_height = ((width as f32 / target_aspect_ratio).ceil() as u32).min(height);
+        let diff = (y_mid as i32 - new_height as i32 / 2).min((height - new_height) as i32);
         if diff >= 0 {
             return (0, diff as u32, width, new_height);
         } else {
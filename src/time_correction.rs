@@ -0,0 +1,564 @@
+/*
+Image database, allows the user to host a database themselves,
+with the possibilities to tag and search after images.
+Copyright (C) 2020 Cybercom group AB, Sweden
+By Christoffer Dahl, Johanna Hultberg, Andreas Runfalk and Margareta Vi
+
+Image database is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::anyhow;
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
+use regex::{NoExpand, Regex};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio_postgres::Client;
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::model::{Entity, EntityFilter, TimeCorrection, TimeCorrectionEntity};
+use crate::util::run_blocking;
+
+/// Larger shifts are most likely typos
+const MAX_SHIFT_DAYS: i64 = 100 * 366;
+
+/// XMP properties holding the capture time, with the namespace of their prefix
+const XMP_DATE_PROPERTIES: &[(&str, &str, &str)] = &[
+    ("exif", "DateTimeOriginal", "http://ns.adobe.com/exif/1.0/"),
+    ("xmp", "CreateDate", "http://ns.adobe.com/xap/1.0/"),
+    (
+        "photoshop",
+        "DateCreated",
+        "http://ns.adobe.com/photoshop/1.0/",
+    ),
+];
+
+/// How the capture times are changed
+#[derive(Clone, Debug, PartialEq)]
+pub enum TimeChange {
+    /// Move every time by the same amount, i.e. when the clock of the camera was wrong
+    Shift(Duration),
+    /// Use the same local time for all. Without an offset, the one each entity has is kept
+    Set {
+        local: NaiveDateTime,
+        offset: Option<i32>,
+    },
+}
+
+impl TimeChange {
+    /// Parse a shift such as `+1h30m`, `-2d` or `-01:00`
+    pub fn parse_shift(value: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid shift {:?}, use i.e. +1h30m or -01:00", value);
+        let value = value.trim();
+        let (sign, rest) = match value.chars().next() {
+            Some('-') => (-1, &value[1..]),
+            Some('+') => (1, &value[1..]),
+            _ => (1, value),
+        };
+        if rest.is_empty() {
+            return Err(invalid().into());
+        }
+
+        let mut seconds = 0i64;
+        if rest.contains(':') {
+            let parts: Vec<&str> = rest.split(':').collect();
+            if parts.len() > 3 {
+                return Err(invalid().into());
+            }
+            for (part, unit) in parts.iter().zip(&[3600, 60, 1]) {
+                seconds = seconds
+                    .saturating_add(i64::from(part.parse::<u32>().map_err(|_| invalid())?) * unit);
+            }
+        } else {
+            let mut number = String::new();
+            for c in rest.chars() {
+                if c.is_ascii_digit() {
+                    number.push(c);
+                    continue;
+                }
+                let unit = match c {
+                    'd' => 24 * 3600,
+                    'h' => 3600,
+                    'm' => 60,
+                    's' => 1,
+                    _ => return Err(invalid().into()),
+                };
+                seconds = seconds.saturating_add(
+                    i64::from(number.parse::<u32>().map_err(|_| invalid())?) * unit,
+                );
+                number.clear();
+            }
+            if !number.is_empty() {
+                return Err(invalid().into());
+            }
+        }
+        if seconds > MAX_SHIFT_DAYS * 24 * 3600 {
+            return Err(anyhow!("Shifts are limited to {} days", MAX_SHIFT_DAYS).into());
+        }
+        Ok(TimeChange::Shift(Duration::seconds(sign * seconds)))
+    }
+
+    /// Parse a time such as `2020-06-01T12:00:00+02:00`. Without an offset it's the local time
+    pub fn parse_set(value: &str) -> Result<Self> {
+        let value = value.trim();
+        if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+            return Ok(TimeChange::Set {
+                local: time.naive_local(),
+                offset: Some(time.offset().local_minus_utc()),
+            });
+        }
+        for format in &["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+            if let Ok(local) = NaiveDateTime::parse_from_str(value, format) {
+                return Ok(TimeChange::Set {
+                    local,
+                    offset: None,
+                });
+            }
+        }
+        Err(anyhow!(
+            "Invalid time {:?}, use i.e. 2020-06-01T12:00:00+02:00",
+            value
+        )
+        .into())
+    }
+
+    /// Returns the new capture time in UTC, local time and the offset between them, `None` when
+    /// there is no time to shift
+    fn apply(
+        &self,
+        entity: &Entity,
+    ) -> Option<(DateTime<Utc>, Option<NaiveDateTime>, Option<i32>)> {
+        match *self {
+            TimeChange::Shift(shift) => {
+                let created = entity.created?.checked_add_signed(shift)?;
+                let created_local = match entity.created_local {
+                    Some(local) => Some(local.checked_add_signed(shift)?),
+                    None => None,
+                };
+                Some((created, created_local, entity.utc_offset))
+            }
+            TimeChange::Set { local, offset } => {
+                // Like at import, a local time without offset is assumed to be UTC
+                let offset = offset.or(entity.utc_offset);
+                let created =
+                    local.checked_sub_signed(Duration::seconds(offset.unwrap_or(0).into()))?;
+                Some((Utc.from_utc_datetime(&created), Some(local), offset))
+            }
+        }
+    }
+}
+
+impl fmt::Display for TimeChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            TimeChange::Shift(shift) => {
+                let seconds = shift.num_seconds();
+                write!(f, "Shift by {}", if seconds < 0 { '-' } else { '+' })?;
+                let mut rest = seconds.abs();
+                for (unit, name) in &[(24 * 3600, 'd'), (3600, 'h'), (60, 'm')] {
+                    if rest >= *unit {
+                        write!(f, "{}{}", rest / unit, name)?;
+                        rest %= unit;
+                    }
+                }
+                if rest > 0 || seconds == 0 {
+                    write!(f, "{}s", rest)?;
+                }
+                Ok(())
+            }
+            TimeChange::Set { local, offset } => {
+                write!(f, "Set to {}", local.format("%Y-%m-%d %H:%M:%S%.f"))?;
+                if let Some(offset) = offset {
+                    write!(f, " {}", format_utc_offset(offset))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The capture times that were, or would be, changed by a correction
+#[derive(Debug, Default)]
+pub struct TimeCorrectionSummary {
+    /// Set once the times have been changed, it's needed to undo them
+    pub id: Option<i32>,
+    pub changes: Vec<TimeCorrectionEntity>,
+    /// Media without a capture time to shift, or whose time was changed again before an undo
+    pub skipped: Vec<i32>,
+    pub sidecars_failed: Vec<(i32, String)>,
+}
+
+impl fmt::Display for TimeCorrectionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in self.changes.iter() {
+            writeln!(
+                f,
+                "{}: {} -> {}",
+                change.eid,
+                format_time(
+                    change.old_created,
+                    change.old_created_local,
+                    change.old_utc_offset
+                ),
+                format_time(
+                    change.new_created,
+                    change.new_created_local,
+                    change.new_utc_offset
+                )
+            )?;
+        }
+        match self.id {
+            Some(id) => writeln!(f, "Changed: {} (correction {})", self.changes.len(), id)?,
+            None => writeln!(f, "To change: {}", self.changes.len())?,
+        }
+        write!(f, "Skipped: {}", self.skipped.len())?;
+        if !self.skipped.is_empty() {
+            let ids: Vec<String> = self.skipped.iter().map(|id| id.to_string()).collect();
+            write!(f, "\n  {}", ids.join(", "))?;
+        }
+        if !self.sidecars_failed.is_empty() {
+            write!(f, "\nSidecars failed: {}", self.sidecars_failed.len())?;
+            for (id, reason) in self.sidecars_failed.iter() {
+                write!(f, "\n  {}: {}", id, reason)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Work out the new capture times of the media matching the filter. Nothing is changed unless
+/// `apply` is set, then the old times are kept so the correction can be undone
+pub async fn correct_times(
+    client: &Client,
+    config: &Config,
+    filter: &EntityFilter,
+    change: &TimeChange,
+    apply: bool,
+    sidecars: bool,
+) -> Result<TimeCorrectionSummary> {
+    let mut summary = TimeCorrectionSummary::default();
+    let mut entities = Vec::new();
+    for entity in Entity::list_filtered(client, filter).await? {
+        match change.apply(&entity) {
+            Some((created, created_local, utc_offset)) => {
+                summary.changes.push(TimeCorrectionEntity {
+                    cid: 0,
+                    eid: entity.id,
+                    old_created: entity.created,
+                    old_created_local: entity.created_local,
+                    new_created: Some(created),
+                    new_created_local: created_local,
+                    old_utc_offset: entity.utc_offset,
+                    new_utc_offset: utc_offset,
+                });
+                entities.push(entity);
+            }
+            None => summary.skipped.push(entity.id),
+        }
+    }
+    if !apply || summary.changes.is_empty() {
+        return Ok(summary);
+    }
+
+    let correction = TimeCorrection::insert(client, &change.to_string(), sidecars).await?;
+    summary.id = Some(correction.id);
+    for (change, mut entity) in summary.changes.iter_mut().zip(entities) {
+        change.cid = correction.id;
+        change.insert(client).await?;
+        entity.created = change.new_created;
+        entity.created_local = change.new_created_local;
+        entity.utc_offset = change.new_utc_offset;
+        entity.save(client).await?;
+        if sidecars {
+            if let Err(err) = write_sidecar(config, &entity).await {
+                summary.sidecars_failed.push((entity.id, err.to_string()));
+            }
+        }
+    }
+    Ok(summary)
+}
+
+/// Put back the capture times from before a correction. Media whose time has been changed again
+/// since is left alone
+pub async fn undo_time_correction(
+    client: &Client,
+    config: &Config,
+    id: i32,
+) -> Result<TimeCorrectionSummary> {
+    let mut correction = TimeCorrection::get(client, id)
+        .await
+        .ok_or(anyhow!("No such time correction {}", id))?;
+    if correction.undone.is_some() {
+        return Err(anyhow!("Time correction {} is already undone", id).into());
+    }
+
+    let mut summary = TimeCorrectionSummary {
+        id: Some(id),
+        ..Default::default()
+    };
+    for change in TimeCorrectionEntity::list_for_correction(client, id).await? {
+        let mut entity = Entity::get(client, change.eid)
+            .await
+            .ok_or(anyhow!("No such entity {}", change.eid))?;
+        if entity.created != change.new_created
+            || entity.created_local != change.new_created_local
+            || entity.utc_offset != change.new_utc_offset
+        {
+            summary.skipped.push(entity.id);
+            continue;
+        }
+        entity.created = change.old_created;
+        entity.created_local = change.old_created_local;
+        entity.utc_offset = change.old_utc_offset;
+        entity.save(client).await?;
+        if correction.sidecars {
+            if let Err(err) = write_sidecar(config, &entity).await {
+                summary.sidecars_failed.push((entity.id, err.to_string()));
+            }
+        }
+        summary.changes.push(TimeCorrectionEntity {
+            old_created: change.new_created,
+            old_created_local: change.new_created_local,
+            new_created: change.old_created,
+            new_created_local: change.old_created_local,
+            old_utc_offset: change.new_utc_offset,
+            new_utc_offset: change.old_utc_offset,
+            ..change
+        });
+    }
+    correction.set_undone(client).await?;
+    Ok(summary)
+}
+
+fn format_utc_offset(offset: i32) -> String {
+    let minutes = offset.abs() / 60;
+    format!(
+        "{}{:02}:{:02}",
+        if offset < 0 { '-' } else { '+' },
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+/// The local time with its offset when known, otherwise UTC
+fn format_time(
+    created: Option<DateTime<Utc>>,
+    created_local: Option<NaiveDateTime>,
+    utc_offset: Option<i32>,
+) -> String {
+    match (created, created_local, utc_offset) {
+        (Some(_), Some(local), Some(offset)) => format!(
+            "{} {}",
+            local.format("%Y-%m-%d %H:%M:%S%.f"),
+            format_utc_offset(offset)
+        ),
+        (Some(_), Some(local), None) => local.format("%Y-%m-%d %H:%M:%S%.f").to_string(),
+        (Some(utc), None, _) => utc.format("%Y-%m-%d %H:%M:%S%.f UTC").to_string(),
+        (None, _, _) => "none".to_string(),
+    }
+}
+
+/// The capture time of an entity in the ISO 8601 format used by XMP
+fn xmp_date(entity: &Entity) -> Option<String> {
+    let created = entity.created?;
+    // XMP dates without an offset are local times
+    Some(match (entity.created_local, entity.utc_offset) {
+        (Some(local), Some(offset)) => format!(
+            "{}{}",
+            local.format("%Y-%m-%dT%H:%M:%S%.f"),
+            format_utc_offset(offset)
+        ),
+        (Some(local), None) => local.format("%Y-%m-%dT%H:%M:%S%.f").to_string(),
+        (None, _) => created.format("%Y-%m-%dT%H:%M:%S%.fZ").to_string(),
+    })
+}
+
+/// Sidecars are either named like the original with `.xmp` added, or with `.xmp` instead of its
+/// extension. New ones get the first kind of name, as with the second a RAW and JPEG pair from
+/// the same shot would share one
+fn sidecar_path(path: &Path) -> PathBuf {
+    let mut appended = path.as_os_str().to_owned();
+    appended.push(".xmp");
+    let appended = PathBuf::from(appended);
+    let replaced = path.with_extension("xmp");
+    if !appended.exists() && replaced.exists() && !shares_stem(path) {
+        replaced
+    } else {
+        appended
+    }
+}
+
+/// Returns true if another file next to `path` has the same name apart from the extension
+fn shares_stem(path: &Path) -> bool {
+    let (dir, stem) = match (path.parent(), path.file_stem()) {
+        (Some(dir), Some(stem)) => (dir, stem),
+        _ => return false,
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // Can't tell, so don't risk writing to the sidecar of another file
+        Err(_) => return true,
+    };
+    entries.filter_map(|entry| entry.ok()).any(|entry| {
+        let other = entry.path();
+        other != path
+            && other.file_stem() == Some(stem)
+            && other
+                .extension()
+                .map_or(true, |ext| !ext.eq_ignore_ascii_case("xmp"))
+    })
+}
+
+/// Write the capture time of an entity to the XMP sidecar of its original, creating the sidecar
+/// if there is none. Only originals in referenced libraries get one, anything written next to
+/// the copies in `dest/` would be taken for an orphan
+async fn write_sidecar(config: &Config, entity: &Entity) -> Result<()> {
+    if !config.is_referenced(&entity.path) {
+        return Err(anyhow!("The original is not in a referenced library").into());
+    }
+    let date = xmp_date(entity).ok_or(anyhow!("No capture time to write"))?;
+    let path = sidecar_path(&entity.path);
+    run_blocking(move || {
+        let xmp = match fs::read_to_string(&path) {
+            Ok(xmp) => set_xmp_dates(&xmp, &date)?,
+            Err(_) if !path.exists() => new_xmp(&date),
+            Err(err) => return Err(err.into()),
+        };
+        // Write to a temporary file first so a failed write never leaves a broken sidecar
+        let file_name = path
+            .file_name()
+            .ok_or(anyhow!("Invalid sidecar path {:?}", path))?;
+        let tmp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+        fs::write(&tmp_path, xmp)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATE: &str = "2020-06-01T12:00:00+02:00";
+
+    fn shift(value: &str) -> Option<Duration> {
+        match TimeChange::parse_shift(value) {
+            Ok(TimeChange::Shift(duration)) => Some(duration),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parse_shift_units() {
+        assert_eq!(shift("+1h30m"), Some(Duration::minutes(90)));
+        assert_eq!(shift("1h30m"), Some(Duration::minutes(90)));
+        assert_eq!(shift("-2d"), Some(Duration::days(-2)));
+        assert_eq!(shift("10s"), Some(Duration::seconds(10)));
+    }
+
+    #[test]
+    fn parse_shift_clock() {
+        assert_eq!(shift("-01:00"), Some(Duration::hours(-1)));
+        assert_eq!(shift("+01:30:15"), Some(Duration::seconds(5415)));
+        assert_eq!(shift("1:2:3:4"), None);
+    }
+
+    #[test]
+    fn parse_shift_empty() {
+        assert_eq!(shift(""), None);
+        assert_eq!(shift("  "), None);
+        assert_eq!(shift("+"), None);
+        assert_eq!(shift("-"), None);
+    }
+
+    #[test]
+    fn parse_shift_garbage() {
+        assert_eq!(shift("one hour"), None);
+        assert_eq!(shift("1x"), None);
+        assert_eq!(shift("1h30"), None);
+        assert_eq!(shift("h"), None);
+        assert_eq!(shift("01:xx"), None);
+        assert_eq!(shift("--1h"), None);
+    }
+
+    #[test]
+    fn parse_shift_limit() {
+        assert_eq!(
+            shift(&format!("{}d", MAX_SHIFT_DAYS)),
+            Some(Duration::days(MAX_SHIFT_DAYS))
+        );
+        assert_eq!(shift(&format!("{}d", MAX_SHIFT_DAYS + 1)), None);
+        assert_eq!(shift("-4294967295d4294967295d"), None);
+        assert_eq!(shift("99999999999h"), None);
+    }
+
+    #[test]
+    fn set_xmp_dates_attributes() {
+        let xmp = concat!(
+            r#"<rdf:Description rdf:about="" xmlns:exif="http://ns.adobe.com/exif/1.0/""#,
+            r#" xmlns:xmp="http://ns.adobe.com/xap/1.0/""#,
+            r#" xmlns:photoshop="http://ns.adobe.com/photoshop/1.0/""#,
+            r#" exif:DateTimeOriginal="2019-01-01T00:00:00" xmp:CreateDate="2019-01-01T00:00:00""#,
+            r#" photoshop:DateCreated="2019-01-01T00:00:00"/>"#,
+        );
+        let updated = set_xmp_dates(xmp, DATE).unwrap();
+        assert_eq!(updated, xmp.replace("2019-01-01T00:00:00", DATE));
+    }
+
+    #[test]
+    fn set_xmp_dates_elements() {
+        let xmp = concat!(
+            r#"<rdf:Description rdf:about="" xmlns:exif="http://ns.adobe.com/exif/1.0/""#,
+            r#" xmlns:xmp="http://ns.adobe.com/xap/1.0/""#,
+            r#" xmlns:photoshop="http://ns.adobe.com/photoshop/1.0/">"#,
+            "\n <exif:DateTimeOriginal>2019-01-01T00:00:00</exif:DateTimeOriginal>",
+            "\n <xmp:CreateDate>\n  2019-01-01T00:00:00\n </xmp:CreateDate>",
+            "\n <photoshop:DateCreated>2019-01-01T00:00:00</photoshop:DateCreated>",
+            "\n</rdf:Description>",
+        );
+        let updated = set_xmp_dates(xmp, DATE).unwrap();
+        assert!(!updated.contains("2019"));
+        assert!(updated.contains(&format!(
+            "<exif:DateTimeOriginal>{}</exif:DateTimeOriginal>",
+            DATE
+        )));
+        assert!(updated.contains(&format!("<xmp:CreateDate>{}</xmp:CreateDate>", DATE)));
+        assert!(updated.contains(&format!(
+            "<photoshop:DateCreated>{}</photoshop:DateCreated>",
+            DATE
+        )));
+    }
+
+    #[test]
+    fn set_xmp_dates_missing() {
+        let xmp = r#"<rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/"/>"#;
+        let updated = set_xmp_dates(xmp, DATE).unwrap();
+        for (prefix, name, namespace) in XMP_DATE_PROPERTIES {
+            assert!(updated.contains(&format!(r#"{}:{}="{}""#, prefix, name, DATE)));
+            assert_eq!(
+                updated
+                    .matches(&format!(r#"xmlns:{}="{}""#, prefix, namespace))
+                    .count(),
+                1
+            );
+        }
+        assert!(updated.ends_with(r#"rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/"/>"#));
+    }
+
+    #[test]
+    fn set_xmp_dates_without_description() {
+        assert!(set_xmp_dates("<x:xmpmeta/>", DATE).is_err());
+    }
+}
@@ -17,7 +17,10 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
+use actix_web::error::BlockingError;
+use actix_web::web;
 use anyhow::anyhow;
+use chrono::{DateTime, Timelike, Utc};
 use futures::FutureExt;
 use std::collections::BTreeSet;
 use std::path::Path;
@@ -38,6 +41,12 @@ pub fn get_media_type<P: AsRef<Path>>(path: P) -> Result<EntityType> {
     }
 }
 
+/// When the file was last changed, truncated to the microseconds the database keeps
+pub fn modified_time<P: AsRef<Path>>(path: P) -> Option<DateTime<Utc>> {
+    let modified = DateTime::<Utc>::from(path.as_ref().metadata().ok()?.modified().ok()?);
+    modified.with_nanosecond(modified.nanosecond() / 1000 * 1000)
+}
+
 /// Helper method to access database the database in a request handler. Use by
 /// adding `db: web::Data<DbConn>` to your request handler's argument list.
 pub async fn get_db(config: Config) -> Result<DbConn> {
@@ -60,6 +69,18 @@ pub async fn get_db(config: Config) -> Result<DbConn> {
     Ok(client)
 }
 
+/// Run CPU or IO heavy work on the blocking thread pool so that it doesn't stall the event loop
+pub async fn run_blocking<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f).await.map_err(|err| match err {
+        BlockingError::Error(err) => err,
+        BlockingError::Canceled => anyhow!("Thread pool is gone").into(),
+    })
+}
+
 pub fn get_differences<'a, T, F: Fn(&T) -> U, U: Ord>(
     curr: &'a [T],
     new: &'a [T],
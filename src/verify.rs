@@ -0,0 +1,316 @@
+/*
+Image database, allows the user to host a database themselves,
+with the possibilities to tag and search after images.
+Copyright (C) 2020 Cybercom group AB, Sweden
+By Christoffer Dahl, Johanna Hultberg, Andreas Runfalk and Margareta Vi
+
+Image database is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tokio_postgres::Client;
+use walkdir::WalkDir;
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::hash::Sha3;
+use crate::model::{Derivative, Entity, EntityFilter};
+use crate::regenerate::regenerate;
+use crate::storage::DEST_DIR;
+use crate::util::run_blocking;
+
+#[derive(Debug)]
+pub enum Problem {
+    MissingOriginal {
+        id: i32,
+        path: PathBuf,
+    },
+    UnreadableOriginal {
+        id: i32,
+        path: PathBuf,
+        reason: String,
+    },
+    SizeMismatch {
+        id: i32,
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    HashMismatch {
+        id: i32,
+        path: PathBuf,
+    },
+    MissingThumbnail {
+        id: i32,
+        path: PathBuf,
+    },
+    MissingPreview {
+        id: i32,
+        path: PathBuf,
+    },
+    MissingDerivative {
+        id: i32,
+        profile: String,
+        path: PathBuf,
+    },
+    /// A file in dest that no entity refers to
+    Orphan {
+        path: PathBuf,
+    },
+    /// A file or directory in dest that couldn't be looked at
+    UnreadableDest {
+        path: PathBuf,
+        reason: String,
+    },
+}
+
+impl Problem {
+    /// Returns the id of the entity if the original can no longer be trusted
+    fn broken_original(&self) -> Option<i32> {
+        match self {
+            Problem::MissingOriginal { id, .. }
+            | Problem::UnreadableOriginal { id, .. }
+            | Problem::SizeMismatch { id, .. }
+            | Problem::HashMismatch { id, .. } => Some(*id),
+            _ => None,
+        }
+    }
+
+    /// Returns the id of the entity if its thumbnail or preview is missing
+    fn missing_derivative(&self) -> Option<i32> {
+        match self {
+            Problem::MissingThumbnail { id, .. }
+            | Problem::MissingPreview { id, .. }
+            | Problem::MissingDerivative { id, .. } => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::MissingOriginal { id, path } => {
+                write!(f, "Missing original (id {}): {:?}", id, path)
+            }
+            Problem::UnreadableOriginal { id, path, reason } => {
+                write!(f, "Unreadable original (id {}): {:?}: {}", id, path, reason)
+            }
+            Problem::SizeMismatch {
+                id,
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Size mismatch (id {}): {:?} is {} bytes, expected {}",
+                id, path, actual, expected
+            ),
+            Problem::HashMismatch { id, path } => {
+                write!(f, "SHA-3 mismatch (id {}): {:?}", id, path)
+            }
+            Problem::MissingThumbnail { id, path } => {
+                write!(f, "Missing thumbnail (id {}): {:?}", id, path)
+            }
+            Problem::MissingPreview { id, path } => {
+                write!(f, "Missing preview (id {}): {:?}", id, path)
+            }
+            Problem::MissingDerivative { id, profile, path } => {
+                write!(f, "Missing {} derivative (id {}): {:?}", profile, id, path)
+            }
+            Problem::Orphan { path } => write!(f, "Orphan: {:?}", path),
+            Problem::UnreadableDest { path, reason } => {
+                write!(f, "Unreadable file in dest: {:?}: {}", path, reason)
+            }
+        }
+    }
+}
+
+async fn check_entity(entity: &Entity, derivatives: &[&Derivative]) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    for derivative in derivatives {
+        // The thumbnail and preview are checked below
+        if derivative.path != entity.thumbnail_path
+            && derivative.path != entity.preview_path
+            && !derivative.path.exists()
+        {
+            problems.push(Problem::MissingDerivative {
+                id: entity.id,
+                profile: derivative.profile.clone(),
+                path: derivative.path.clone(),
+            });
+        }
+    }
+    if !entity.thumbnail_path.exists() {
+        problems.push(Problem::MissingThumbnail {
+            id: entity.id,
+            path: entity.thumbnail_path.clone(),
+        });
+    }
+    if !entity.preview_path.exists() {
+        problems.push(Problem::MissingPreview {
+            id: entity.id,
+            path: entity.preview_path.clone(),
+        });
+    }
+
+    let size = match entity.path.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(_) => {
+            problems.push(Problem::MissingOriginal {
+                id: entity.id,
+                path: entity.path.clone(),
+            });
+            return problems;
+        }
+    };
+    if size != entity.size {
+        problems.push(Problem::SizeMismatch {
+            id: entity.id,
+            path: entity.path.clone(),
+            expected: entity.size,
+            actual: size,
+        });
+    }
+
+    let hash_path = entity.path.clone();
+    match run_blocking(move || Sha3::from_path_blocking(&hash_path)).await {
+        Ok(sha3) if sha3 != entity.sha3 => problems.push(Problem::HashMismatch {
+            id: entity.id,
+            path: entity.path.clone(),
+        }),
+        Ok(_) => {}
+        Err(err) => problems.push(Problem::UnreadableOriginal {
+            id: entity.id,
+            path: entity.path.clone(),
+            reason: err.to_string(),
+        }),
+    }
+    problems
+}
+
+/// Check that the files of every entity are present and intact, and look for files in dest that
+/// don't belong to any entity
+pub async fn verify(client: &Client, workers: usize) -> Result<Vec<Problem>> {
+    // Files that are added while verifying may belong to media that isn't listed yet
+    let started = SystemTime::now();
+    let entities: Vec<Entity> = Entity::list_desc(client).await?.try_collect().await?;
+    let derivatives = Derivative::list(client).await?;
+    let mut by_eid: HashMap<i32, Vec<&Derivative>> = HashMap::new();
+    for derivative in derivatives.iter() {
+        by_eid.entry(derivative.eid).or_default().push(derivative);
+    }
+
+    let mut problems: Vec<Problem> = stream::iter(entities.iter())
+        .map(|entity| {
+            let derivatives = by_eid.get(&entity.id).map(|d| &d[..]).unwrap_or(&[]);
+            check_entity(entity, derivatives)
+        })
+        .buffer_unordered(workers.max(1))
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let referenced: HashSet<PathBuf> = entities
+        .iter()
+        .flat_map(|e| vec![&e.path, &e.thumbnail_path, &e.preview_path])
+        .cloned()
+        .chain(derivatives.iter().map(|d| d.path.clone()))
+        .chain(derivatives.iter().flat_map(Derivative::alternative_paths))
+        .filter_map(|path| path.canonicalize().ok())
+        .collect();
+    for entry in WalkDir::new(DEST_DIR) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                problems.push(Problem::UnreadableDest {
+                    path: err.path().map(PathBuf::from).unwrap_or_default(),
+                    reason: err.to_string(),
+                });
+                continue;
+            }
+        };
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let checked = entry
+            .metadata()
+            .map_err(|err| err.to_string())
+            .and_then(|metadata| metadata.modified().map_err(|err| err.to_string()))
+            .and_then(|modified| {
+                let path = entry.path().canonicalize().map_err(|err| err.to_string())?;
+                Ok((path, modified))
+            });
+        match checked {
+            Ok((_, modified)) if modified >= started => {}
+            Ok((path, _)) if !referenced.contains(&path) => problems.push(Problem::Orphan { path }),
+            Ok(_) => {}
+            Err(reason) => problems.push(Problem::UnreadableDest {
+                path: entry.path().to_path_buf(),
+                reason,
+            }),
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Regenerate missing thumbnails and previews for entities whose original is intact, and
+/// optionally delete orphans
+pub async fn repair(
+    client: &Client,
+    config: &Config,
+    problems: &[Problem],
+    regenerate_missing: bool,
+    delete_orphans: bool,
+    workers: usize,
+) -> Result<()> {
+    if regenerate_missing {
+        let broken: HashSet<i32> = problems
+            .iter()
+            .filter_map(Problem::broken_original)
+            .collect();
+        let mut ids: Vec<i32> = problems
+            .iter()
+            .filter_map(Problem::missing_derivative)
+            .filter(|id| !broken.contains(id))
+            .collect();
+        ids.sort();
+        ids.dedup();
+
+        if !ids.is_empty() {
+            let filter = EntityFilter {
+                ids,
+                ..Default::default()
+            };
+            println!("{}", regenerate(client, config, &filter, workers).await?);
+        }
+    }
+
+    if delete_orphans {
+        for problem in problems {
+            if let Problem::Orphan { path } = problem {
+                fs::remove_file(path)?;
+                println!("Deleted {:?}", path);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,98 @@
+/*
+Image database, allows the user to host a database themselves,
+with the possibilities to tag and search after images.
+Copyright (C) 2020 Cybercom group AB, Sweden
+By Christoffer Dahl, Johanna Hultberg, Andreas Runfalk and Margareta Vi
+
+Image database is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use futures::channel::mpsc::unbounded;
+use futures::StreamExt;
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+use tokio_postgres::Client;
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::import::populate_database;
+
+/// Returns the paths that should be (re)imported because of the given event. Notify only emits
+/// create and write events once the file has been left alone for the debounce delay, so we won't
+/// see partially written files here
+fn paths_to_import(event: DebouncedEvent, dirs: &[PathBuf]) -> Vec<PathBuf> {
+    match event {
+        DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => vec![path],
+        DebouncedEvent::Rename(_, dest) => vec![dest],
+        DebouncedEvent::Rescan => dirs.to_vec(),
+        DebouncedEvent::Error(err, path) => {
+            println!("Watch error for {:?}: {}", path, err);
+            vec![]
+        }
+        _ => vec![],
+    }
+}
+
+pub async fn watch_directories(
+    client: &Client,
+    config: &Config,
+    dirs: &[PathBuf],
+    workers: usize,
+    delay: Duration,
+) -> Result<()> {
+    let (tx, rx) = channel();
+    let mut watcher = watcher(tx, delay)?;
+    for dir in dirs {
+        watcher.watch(dir, RecursiveMode::Recursive)?;
+    }
+
+    // Notify uses a blocking channel, so we forward its events to one we can await
+    let (async_tx, mut async_rx) = unbounded();
+    thread::spawn(move || {
+        for event in rx.iter() {
+            if async_tx.unbounded_send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Files may have been added while we weren't running. Anything that is already imported is
+    // skipped by the SHA-3 check
+    println!("Scanning {:?} for new media", dirs);
+    println!(
+        "{}",
+        populate_database(client, config, dirs, workers).await?
+    );
+
+    println!("Watching {:?}", dirs);
+    while let Some(event) = async_rx.next().await {
+        let mut paths = paths_to_import(event, dirs);
+
+        // Import everything that has queued up while we were busy as one batch
+        while let Ok(Some(event)) = async_rx.try_next() {
+            paths.extend(paths_to_import(event, dirs));
+        }
+        paths.sort();
+        paths.dedup();
+
+        let summary = populate_database(client, config, &paths, workers).await?;
+        if !summary.is_empty() {
+            println!("{}", summary);
+        }
+    }
+
+    Ok(())
+}
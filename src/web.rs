@@ -20,22 +20,34 @@ along with this program. If not, see <https://www.gnu.org/licenses/>.
 use actix_files::NamedFile;
 use actix_multipart::Multipart;
 use actix_protobuf::ProtoBuf;
+use actix_web::http::header;
 use actix_web::{middleware::Logger, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use anyhow::anyhow;
 use futures::{Stream, StreamExt, TryStreamExt};
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
-use std::path::PathBuf;
+use std::path::Path;
 use std::pin::Pin;
+use std::sync::Arc;
 
 use crate::api;
-use crate::config::Config;
+use crate::config::{Config, Fit, OutputFormat};
+use crate::dump::dump_metadata;
 use crate::error::Result;
 use crate::hash::Sha3;
 use crate::metadata::Metadata;
-use crate::model::{Entity, Tag, TagToEntity};
-use crate::thumbnail::{copy_and_create_thumbnail_bytes, file_type_from_path};
-use crate::util::{get_db, get_differences, get_media_type, DbConn};
+use crate::model::{
+    Derivative, Edit, Entity, EntityFilter, EntityMetadata, RawMetadata, Tag, TagToEntity,
+};
+use crate::regenerate::{regenerate_entities, set_edits, set_rotation};
+use crate::render::{export, render, RenderCache, RenderParams};
+use crate::storage::original_name;
+use crate::thumbnail::{
+    copy_and_create_thumbnail_bytes, file_type_from_bytes, warn_on_extension_mismatch,
+};
+use crate::time_correction::{correct_times, undo_time_correction, TimeChange};
+use crate::util::{get_db, get_differences, get_media_type, modified_time, run_blocking, DbConn};
 
 fn make_protobuf_response<T: prost::Message>(pb: &T) -> Result<impl Responder> {
     let mut buf_mut = Vec::new();
@@ -45,11 +57,89 @@ fn make_protobuf_response<T: prost::Message>(pb: &T) -> Result<impl Responder> {
         .body(buf_mut))
 }
 
-async fn show_media(req: HttpRequest) -> Result<NamedFile> {
-    // NOTE: Once we have folders here we have to be careful to not introduce security holes
-    let path: PathBuf = req.match_info().query("path").parse()?;
-    let path = std::path::Path::new("dest").join(path.file_name().ok_or(anyhow!("No such image"))?);
-    Ok(NamedFile::open(path)?)
+/// The quality the client gives a media type in its `Accept` header, from 0 to 1. Only exact
+/// matches count, as browsers that say `image/*` often can't show the newer formats
+fn accept_quality(accept: &str, mime_type: &str) -> Option<f32> {
+    accept.split(',').find_map(|range| {
+        let mut parts = range.split(';');
+        if !parts.next()?.trim().eq_ignore_ascii_case(mime_type) {
+            return None;
+        }
+        let quality = parts
+            .filter_map(|param| {
+                let mut kv = param.splitn(2, '=');
+                match (kv.next()?.trim(), kv.next()) {
+                    ("q", Some(value)) | ("Q", Some(value)) => value.trim().parse::<f32>().ok(),
+                    _ => None,
+                }
+            })
+            .next()
+            .unwrap_or(1.0);
+        Some(quality.max(0.0).min(1.0))
+    })
+}
+
+/// Pick the format with the highest quality among the given ones that the client accepts. AVIF
+/// wins over WebP when they're equal. JPEG and PNG are assumed to always be supported, so
+/// they're never returned
+fn negotiate_format(req: &HttpRequest, available: &[OutputFormat]) -> Option<OutputFormat> {
+    let accept = req.headers().get(header::ACCEPT)?.to_str().ok()?;
+    let mut best: Option<(OutputFormat, f32)> = None;
+    for format in [OutputFormat::Avif, OutputFormat::Webp].iter().copied() {
+        if !available.contains(&format) {
+            continue;
+        }
+        match accept_quality(accept, format.mime_type()) {
+            Some(quality) if quality > 0.0 && best.map_or(true, |(_, q)| quality > q) => {
+                best = Some((format, quality))
+            }
+            _ => {}
+        }
+    }
+    best.map(|(format, _)| format)
+}
+
+async fn show_media(req: HttpRequest, db: web::Data<DbConn>) -> Result<HttpResponse> {
+    // Paths are only ever taken from the entity, never from the request
+    let id = req.match_info().query("id").parse::<i32>()?;
+    let entity = Entity::get(&db, id).await.ok_or(anyhow!("No such media"))?;
+    let path = match req.match_info().query("kind") {
+        "original" => entity.path,
+        profile => match Derivative::get(&db, id, profile).await {
+            Some(derivative) => {
+                let available: Vec<OutputFormat> = derivative
+                    .alternatives
+                    .iter()
+                    .filter_map(|extension| OutputFormat::from_extension(extension))
+                    .collect();
+                match negotiate_format(&req, &available) {
+                    Some(format) => derivative.path.with_extension(format.extension()),
+                    None => derivative.path,
+                }
+            }
+            // Media imported before derivatives were tracked only has these two
+            None if profile == "thumbnail" => entity.thumbnail_path,
+            None if profile == "preview" => entity.preview_path,
+            None => return Err(anyhow!("No such asset").into()),
+        },
+    };
+
+    // Originals in referenced libraries live outside of dest, so make sure we only ever serve
+    // files from places we manage or have been told about
+    let config = req
+        .app_data::<Config>()
+        .ok_or(anyhow!("Missing configuration"))?;
+    if !config.is_servable(&path) {
+        return Err(anyhow!("No such asset").into());
+    }
+    let mut response = NamedFile::open(path)?
+        .into_response(&req)
+        .map_err(|err| anyhow!("{}", err))?;
+    // The same URL gives different files depending on what the client accepts
+    response
+        .headers_mut()
+        .insert(header::VARY, header::HeaderValue::from_static("accept"));
+    Ok(response)
 }
 
 async fn static_html() -> Result<NamedFile> {
@@ -100,10 +190,21 @@ async fn api_media_update(
         TagToEntity::delete(&db, tag.id, db_entity.id).await?;
     }
 
-    make_protobuf_response(&api::Entity::new_from_db(db_entity, new_tags)?)
+    let metadata = EntityMetadata::get(&db, db_entity.id)
+        .await
+        .map(|m| m.metadata);
+    make_protobuf_response(&api::Entity::new_from_db(db_entity, new_tags, metadata)?)
 }
 
-async fn media_upload(db: web::Data<DbConn>, mut payload: Multipart) -> Result<impl Responder> {
+async fn media_upload(
+    req: HttpRequest,
+    db: web::Data<DbConn>,
+    mut payload: Multipart,
+) -> Result<impl Responder> {
+    let config = req
+        .app_data::<Config>()
+        .ok_or(anyhow!("Missing configuration"))?;
+
     // iterate over multipart stream
     while let Some(item) = payload.next().await {
         let mut field = item.map_err(|x| anyhow!("{}", x))?;
@@ -120,9 +221,12 @@ async fn media_upload(db: web::Data<DbConn>, mut payload: Multipart) -> Result<i
             image_chunks.append(&mut data.to_vec());
         }
 
-        if file_type_from_path(&file_name).is_none() {
-            println!("Ignoring {:?}", file_name);
-            continue;
+        match file_type_from_bytes(&file_name, &image_chunks) {
+            Some(file_type) => warn_on_extension_mismatch(&file_name, &file_type),
+            None => {
+                println!("Ignoring {:?}", file_name);
+                continue;
+            }
         }
 
         let sha3 = Sha3::from_reader(image_chunks.as_slice()).await?;
@@ -132,39 +236,51 @@ async fn media_upload(db: web::Data<DbConn>, mut payload: Multipart) -> Result<i
         }
 
         println!("Making thumbnail for {:?}", &file_name);
-        let (img, thumbnail, preview) =
-            match copy_and_create_thumbnail_bytes(file_name, &image_chunks) {
-                Ok((i, t, p)) => (i, t, p),
-                Err(err) => {
-                    println!("Failed: {}", err);
-                    continue;
-                }
-            };
-
-        let path = format!("./dest/{}", &file_name);
-
-        let mut created = None;
-        let mut location = None;
+        let storage = match copy_and_create_thumbnail_bytes(
+            file_name,
+            &image_chunks,
+            &sha3,
+            &config.derivatives,
+        ) {
+            Ok(storage) => storage,
+            Err(err) => {
+                println!("Failed: {}", err);
+                continue;
+            }
+        };
 
-        if let Ok(metadata) = Metadata::from_file(&path) {
-            created = metadata.date_time;
-            location = metadata.gps_location;
-        }
+        let metadata = Metadata::from_file(&storage.original).ok();
+        let created = metadata.as_ref().and_then(|m| m.date_time);
+        let created_local = metadata.as_ref().and_then(|m| m.local_date_time);
+        let utc_offset = metadata.as_ref().and_then(|m| m.utc_offset);
+        let location = metadata.as_ref().and_then(|m| m.gps_location.clone());
 
-        let media_type = get_media_type(&path)?;
+        let media_type = get_media_type(&storage.original)?;
 
-        Entity::insert(
+        let entity = Entity::insert(
             &db,
             media_type,
-            &img,
-            &thumbnail,
-            &preview,
+            &storage.original,
+            &storage.thumbnail,
+            &storage.preview,
             image_chunks.len().try_into().unwrap(),
             &sha3,
             &created,
+            &created_local,
+            &utc_offset,
             &location,
+            &original_name(file_name),
+            &storage.blurhash,
+            &modified_time(&storage.original),
         )
         .await?;
+        Derivative::replace_for_eid(&db, entity.id, &storage.derivatives).await?;
+        if let Some(ref metadata) = metadata {
+            EntityMetadata::set(&db, entity.id, metadata).await?;
+        }
+        if let Ok(raw_metadata) = dump_metadata(&storage.original) {
+            RawMetadata::set(&db, entity.id, &raw_metadata).await?;
+        }
     }
     Ok(HttpResponse::Ok())
 }
@@ -172,6 +288,41 @@ async fn media_upload(db: web::Data<DbConn>, mut payload: Multipart) -> Result<i
 #[derive(Debug, Deserialize)]
 struct SearchQuery {
     q: Option<String>,
+    min_width: Option<u32>,
+    min_height: Option<u32>,
+    min_iso: Option<u32>,
+    max_iso: Option<u32>,
+    flash: Option<bool>,
+    camera_make: Option<String>,
+    camera_model: Option<String>,
+    lens: Option<String>,
+    min_focal_length: Option<f32>,
+    max_focal_length: Option<f32>,
+    /// JSON the raw metadata must contain
+    raw: Option<String>,
+}
+
+impl SearchQuery {
+    fn to_filter(&self) -> Result<EntityFilter> {
+        Ok(EntityFilter {
+            min_width: self.min_width,
+            min_height: self.min_height,
+            min_iso: self.min_iso,
+            max_iso: self.max_iso,
+            flash: self.flash,
+            camera_make: self.camera_make.clone(),
+            camera_model: self.camera_model.clone(),
+            lens_model: self.lens.clone(),
+            min_focal_length: self.min_focal_length,
+            max_focal_length: self.max_focal_length,
+            raw_metadata: self
+                .raw
+                .as_ref()
+                .map(|raw| serde_json::from_str(raw))
+                .transpose()?,
+            ..Default::default()
+        })
+    }
 }
 
 async fn api_media_list(
@@ -186,9 +337,54 @@ async fn api_media_list(
             Box::pin(Entity::list_desc(&db).await?)
         };
 
-    let mut entities_pb = api::Entities::default();
+    // Tags are searched for first, the metadata criteria narrow that down further
+    let filter = query_params.to_filter()?;
+    let matching: Option<HashSet<i32>> = if filter.has_metadata_criteria() {
+        Some(
+            Entity::list_filtered(&db, &filter)
+                .await?
+                .into_iter()
+                .map(|e| e.id)
+                .collect(),
+        )
+    } else {
+        None
+    };
+
+    let mut db_entities = Vec::new();
     while let Some(entity) = entities.next().await.transpose()? {
-        entities_pb.add(api::Entity::try_from(entity)?);
+        if matching
+            .as_ref()
+            .map_or(true, |ids| ids.contains(&entity.id))
+        {
+            db_entities.push(entity);
+        }
+    }
+
+    let ids: Vec<i32> = db_entities.iter().map(|e| e.id).collect();
+    let mut metadata: HashMap<i32, Metadata> = EntityMetadata::list_from_eids(&db, &ids)
+        .await?
+        .into_iter()
+        .map(|m| (m.eid, m.metadata))
+        .collect();
+    let mut pb_entities = Vec::new();
+    for entity in db_entities {
+        let id = entity.id;
+        pb_entities.push(api::Entity::with_metadata(entity, metadata.remove(&id))?);
+    }
+
+    let mut derivatives: HashMap<i32, Vec<Derivative>> = HashMap::new();
+    for derivative in Derivative::list_from_eids(&db, &ids).await? {
+        derivatives
+            .entry(derivative.eid)
+            .or_default()
+            .push(derivative);
+    }
+
+    let mut entities_pb = api::Entities::default();
+    for mut pb_entity in pb_entities {
+        pb_entity.add_derivatives(derivatives.remove(&pb_entity.id).unwrap_or_default())?;
+        entities_pb.add(pb_entity);
     }
     make_protobuf_response(&entities_pb)
 }
@@ -205,10 +401,310 @@ async fn api_media_get(req: HttpRequest, db: web::Data<DbConn>) -> Result<impl R
         tags_pb.add(api::Tag::try_from(tag)?);
     }
 
-    let pb_entity = api::create_entity_with_metadata(entity, tags_pb)?;
+    let metadata = EntityMetadata::get(&db, eid).await.map(|m| m.metadata);
+    let mut pb_entity = api::create_entity_with_metadata(entity, tags_pb, metadata)?;
+    pb_entity.add_derivatives(Derivative::list_from_eids(&db, &[eid]).await?)?;
     make_protobuf_response(&pb_entity)
 }
 
+/// Every EXIF, XMP and IPTC field of the media. This is JSON rather than protobuf since the fields
+/// differ between cameras and programs
+async fn api_media_raw_metadata(req: HttpRequest, db: web::Data<DbConn>) -> Result<impl Responder> {
+    let id = req.match_info().query("id").parse::<i32>()?;
+    let raw_metadata = RawMetadata::get(&db, id)
+        .await
+        .ok_or(anyhow!("No raw metadata for {}", id))?;
+    Ok(HttpResponse::Ok().json(raw_metadata.data))
+}
+
+#[derive(Debug, Deserialize)]
+struct RenderQuery {
+    w: Option<u32>,
+    h: Option<u32>,
+    #[serde(default)]
+    fit: Fit,
+    /// Picked from the Accept header when not given
+    format: Option<OutputFormat>,
+}
+
+async fn api_media_render(
+    req: HttpRequest,
+    db: web::Data<DbConn>,
+    query_params: web::Query<RenderQuery>,
+) -> Result<impl Responder> {
+    let id = req.match_info().query("id").parse::<i32>()?;
+    let entity = Entity::get(&db, id).await.ok_or(anyhow!("No such media"))?;
+    let config = req
+        .app_data::<Config>()
+        .ok_or(anyhow!("Missing configuration"))?;
+    let cache = req
+        .app_data::<Arc<RenderCache>>()
+        .ok_or(anyhow!("Missing render cache"))?
+        .clone();
+
+    let edits = Edit::operations_for(&db, id).await?;
+    let query = query_params.into_inner();
+    let format = query.format.unwrap_or_else(|| {
+        negotiate_format(&req, &[OutputFormat::Avif, OutputFormat::Webp]).unwrap_or_default()
+    });
+    let params = RenderParams::new(query.w, query.h, query.fit, format)?;
+    let quality = config.render.quality;
+    let raw_source = config.derivatives.raw_source.clone();
+    let content_type = params.format.mime_type();
+    let data = run_blocking(move || {
+        Ok(render(
+            &cache,
+            &entity,
+            &edits,
+            &params,
+            quality,
+            &raw_source,
+        )?)
+    })
+    .await?;
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .header(header::VARY, "accept")
+        .body(data))
+}
+
+async fn api_media_edits_get(req: HttpRequest, db: web::Data<DbConn>) -> Result<impl Responder> {
+    let id = req.match_info().query("id").parse::<i32>()?;
+    let edits_pb = match Edit::get(&db, id).await {
+        Some(edit) => api::Edits::try_from(edit)?,
+        None => api::Edits::empty(id),
+    };
+    make_protobuf_response(&edits_pb)
+}
+
+async fn api_media_edits_update(
+    req: HttpRequest,
+    db: web::Data<DbConn>,
+    ProtoBuf(edits_pb): ProtoBuf<api::Edits>,
+) -> Result<impl Responder> {
+    let id = req.match_info().query("id").parse::<i32>()?;
+    let config = req
+        .app_data::<Config>()
+        .ok_or(anyhow!("Missing configuration"))?;
+
+    match set_edits(&db, config, id, &edits_pb.to_operations()?).await? {
+        Some(edit) => make_protobuf_response(&api::Edits::try_from(edit)?),
+        None => make_protobuf_response(&api::Edits::empty(id)),
+    }
+}
+
+async fn api_media_edits_reset(req: HttpRequest, db: web::Data<DbConn>) -> Result<impl Responder> {
+    let id = req.match_info().query("id").parse::<i32>()?;
+    let config = req
+        .app_data::<Config>()
+        .ok_or(anyhow!("Missing configuration"))?;
+
+    set_edits(&db, config, id, &[]).await?;
+    make_protobuf_response(&api::Edits::empty(id))
+}
+
+async fn api_media_rotation_update(
+    req: HttpRequest,
+    db: web::Data<DbConn>,
+    ProtoBuf(rotation_pb): ProtoBuf<api::RotationOverride>,
+) -> Result<impl Responder> {
+    let id = req.match_info().query("id").parse::<i32>()?;
+    let config = req
+        .app_data::<Config>()
+        .ok_or(anyhow!("Missing configuration"))?;
+
+    let entity = set_rotation(&db, config, id, rotation_pb.to_rotation()?).await?;
+    let tags: Vec<Tag> = Tag::get_from_eid(&db, id).await?.try_collect().await?;
+    let metadata = EntityMetadata::get(&db, id).await.map(|m| m.metadata);
+    make_protobuf_response(&api::Entity::new_from_db(entity, tags, metadata)?)
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: OutputFormat,
+    quality: Option<u8>,
+}
+
+async fn api_media_export(
+    req: HttpRequest,
+    db: web::Data<DbConn>,
+    query_params: web::Query<ExportQuery>,
+) -> Result<impl Responder> {
+    let id = req.match_info().query("id").parse::<i32>()?;
+    let entity = Entity::get(&db, id).await.ok_or(anyhow!("No such media"))?;
+    let config = req
+        .app_data::<Config>()
+        .ok_or(anyhow!("Missing configuration"))?;
+    let cache = req
+        .app_data::<Arc<RenderCache>>()
+        .ok_or(anyhow!("Missing render cache"))?
+        .clone();
+
+    let edits = Edit::operations_for(&db, id).await?;
+    let query = query_params.into_inner();
+    let format = query.format;
+    let quality = query.quality.unwrap_or(config.render.quality);
+    let raw_source = config.derivatives.raw_source.clone();
+
+    // Name the download after the original, without anything that would break the header
+    let stem = Path::new(&entity.original_name)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(|stem| stem.replace(|c: char| c == '"' || c.is_control(), "_"))
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or_else(|| id.to_string());
+    let disposition = format!("attachment; filename=\"{}.{}\"", stem, format.extension());
+
+    let data = run_blocking(move || {
+        Ok(export(
+            &cache,
+            &entity,
+            &edits,
+            format,
+            quality,
+            &raw_source,
+        )?)
+    })
+    .await?;
+    Ok(HttpResponse::Ok()
+        .content_type(format.mime_type())
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .body(data))
+}
+
+/// Selects the media the admin endpoints work on
+#[derive(Debug, Deserialize)]
+struct FilterQuery {
+    /// Comma separated list of ids
+    ids: Option<String>,
+    #[serde(rename = "type")]
+    media_type: Option<String>,
+    tag: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    camera_model: Option<String>,
+}
+
+impl FilterQuery {
+    fn to_filter(&self) -> Result<EntityFilter> {
+        let mut filter = EntityFilter::default();
+        if let Some(ref ids) = self.ids {
+            for id in ids.split(',') {
+                filter.ids.push(id.trim().parse()?);
+            }
+        }
+        if let Some(ref media_type) = self.media_type {
+            filter.media_type = Some(media_type.parse()?);
+        }
+        if let Some(ref tag) = self.tag {
+            filter.tag = Some(Tag::canonical_name(tag)?);
+        }
+        if let Some(ref from) = self.from {
+            filter.created_from = Some(from.parse()?);
+        }
+        if let Some(ref to) = self.to {
+            filter.created_to = Some(to.parse()?);
+        }
+        filter.camera_model = self.camera_model.clone();
+        Ok(filter)
+    }
+}
+
+/// Number of media regenerated by each admin request, as they can take a while each
+const REGENERATE_BATCH_SIZE: usize = 50;
+
+#[derive(Debug, Deserialize)]
+struct RegenerateQuery {
+    /// Only media with a higher id, to continue where the previous batch stopped
+    after: Option<i32>,
+}
+
+async fn api_admin_regenerate(
+    req: HttpRequest,
+    db: web::Data<DbConn>,
+    filter_params: web::Query<FilterQuery>,
+    query_params: web::Query<RegenerateQuery>,
+) -> Result<impl Responder> {
+    let config = req
+        .app_data::<Config>()
+        .ok_or(anyhow!("Missing configuration"))?;
+    let after = query_params.after.unwrap_or(0);
+    // Entities are listed by id, so the batches never overlap
+    let mut entities: Vec<Entity> = Entity::list_filtered(&db, &filter_params.to_filter()?)
+        .await?
+        .into_iter()
+        .filter(|e| e.id > after)
+        .collect();
+    let next = if entities.len() > REGENERATE_BATCH_SIZE {
+        entities.truncate(REGENERATE_BATCH_SIZE);
+        entities.last().map(|e| e.id).unwrap_or(0)
+    } else {
+        0
+    };
+    let summary = regenerate_entities(&db, config, entities, config.import.workers).await?;
+
+    let mut result_pb = api::RegenerateResult::from(summary);
+    result_pb.next = next;
+    make_protobuf_response(&result_pb)
+}
+
+#[derive(Debug, Deserialize)]
+struct TimeCorrectionQuery {
+    /// Amount to shift by, i.e. 1h30m or -01:00
+    shift: Option<String>,
+    /// Time to set, i.e. 2020-06-01T12:00:00+02:00
+    set: Option<String>,
+    /// The changes are only returned unless this is set
+    #[serde(default)]
+    apply: bool,
+    #[serde(default)]
+    sidecars: bool,
+}
+
+impl TimeCorrectionQuery {
+    fn to_change(&self) -> Result<TimeChange> {
+        match (&self.shift, &self.set) {
+            (Some(shift), None) => TimeChange::parse_shift(shift),
+            (None, Some(set)) => TimeChange::parse_set(set),
+            _ => Err(anyhow!("Either shift or set is required").into()),
+        }
+    }
+}
+
+async fn api_admin_time_correction(
+    req: HttpRequest,
+    db: web::Data<DbConn>,
+    filter_params: web::Query<FilterQuery>,
+    query_params: web::Query<TimeCorrectionQuery>,
+) -> Result<impl Responder> {
+    let config = req
+        .app_data::<Config>()
+        .ok_or(anyhow!("Missing configuration"))?;
+    let summary = correct_times(
+        &db,
+        config,
+        &filter_params.to_filter()?,
+        &query_params.to_change()?,
+        query_params.apply,
+        query_params.sidecars,
+    )
+    .await?;
+    make_protobuf_response(&api::TimeCorrection::try_from(summary)?)
+}
+
+async fn api_admin_time_correction_undo(
+    req: HttpRequest,
+    db: web::Data<DbConn>,
+) -> Result<impl Responder> {
+    let config = req
+        .app_data::<Config>()
+        .ok_or(anyhow!("Missing configuration"))?;
+    let id = req.match_info().query("id").parse::<i32>()?;
+    let summary = undo_time_correction(&db, config, id).await?;
+    make_protobuf_response(&api::TimeCorrection::try_from(summary)?)
+}
+
 async fn api_tags_list(db: web::Data<DbConn>) -> Result<impl Responder> {
     let mut tags = Box::pin(Tag::list(&db).await?);
     let mut tags_pb = api::Tags::default();
@@ -262,6 +758,9 @@ async fn api_media_delete(
 }
 
 pub async fn run_server(config: Config) -> Result<()> {
+    // Shared between all workers, so the size limit of the cache holds
+    let render_cache = Arc::new(RenderCache::open(&config.render)?);
+
     Ok(HttpServer::new(move || {
         // We need this here to ensure ownership for the data_factory callback to move this into
         // itself
@@ -270,6 +769,7 @@ pub async fn run_server(config: Config) -> Result<()> {
         App::new()
             .wrap(Logger::default())
             .app_data(config.clone())
+            .app_data(render_cache.clone())
             .data_factory(move || get_db(get_db_config.clone()))
             .route("/", web::get().to(static_html))
             .route("/tags", web::get().to(static_html))
@@ -278,11 +778,30 @@ pub async fn run_server(config: Config) -> Result<()> {
             .route("/media/{id}", web::get().to(static_html))
             .route("/media", web::get().to(static_html))
             .route("upload", web::get().to(static_html))
-            .route("/assets/{path:.*}", web::get().to(show_media))
+            .route("/assets/{id}/{kind}", web::get().to(show_media))
             .route("/static/{file}", web::get().to(static_file))
             .route("/api/media", web::get().to(api_media_list))
             .route("/api/media/{id}", web::get().to(api_media_get))
             .route("/api/media/{id}", web::put().to(api_media_update))
+            .route("/api/media/{id}/render", web::get().to(api_media_render))
+            .route("/api/media/{id}/edits", web::get().to(api_media_edits_get))
+            .route(
+                "/api/media/{id}/edits",
+                web::put().to(api_media_edits_update),
+            )
+            .route(
+                "/api/media/{id}/edits",
+                web::delete().to(api_media_edits_reset),
+            )
+            .route("/api/media/{id}/export", web::get().to(api_media_export))
+            .route(
+                "/api/media/{id}/metadata/raw",
+                web::get().to(api_media_raw_metadata),
+            )
+            .route(
+                "/api/media/{id}/rotation",
+                web::put().to(api_media_rotation_update),
+            )
             .route("/api/tags", web::get().to(api_tags_list))
             .route(
                 "/api/tags/autocomplete",
@@ -291,6 +810,18 @@ pub async fn run_server(config: Config) -> Result<()> {
             .route("/api/tags/{name}", web::get().to(api_tag_get_by_name))
             .route("/api/media/delete/{id}", web::put().to(api_media_delete))
             .route("/api/tags", web::post().to(api_tags_add))
+            .route(
+                "/api/admin/regenerate",
+                web::post().to(api_admin_regenerate),
+            )
+            .route(
+                "/api/admin/time-corrections",
+                web::post().to(api_admin_time_correction),
+            )
+            .route(
+                "/api/admin/time-corrections/{id}",
+                web::delete().to(api_admin_time_correction_undo),
+            )
     })
     .bind("127.0.0.1:5000")?
     .run()